@@ -1,10 +1,12 @@
-use crate::AWS_FUNCTION_NAME;
+use crate::backend::AnalysisBackend;
 use bytes::Bytes;
 use log::{debug, error, warn};
 use rusoto_core::Region;
 use rusoto_lambda::{InvocationRequest, Lambda, LambdaClient};
 use serde::{Deserialize, Serialize};
+use serenity::async_trait;
 use std::io;
+use std::ops::RangeInclusive;
 use std::time::Duration;
 use tiltak::position::Komi;
 
@@ -12,6 +14,29 @@ use tiltak::position::Komi;
 pub enum TimeControl {
     FixedNodes(u64),
     Time(Duration, Duration), // Total time left, increment
+    MoveTime(Duration),       // Time to search the position for
+}
+
+impl TimeControl {
+    /// How long to search a single position with this much time on the clock
+    pub fn move_time(&self) -> Option<Duration> {
+        match self {
+            TimeControl::FixedNodes(_) => None,
+            TimeControl::Time(time_left, increment) => Some(*time_left / 20 + *increment / 2),
+            TimeControl::MoveTime(move_time) => Some(*move_time),
+        }
+    }
+}
+
+// Enough candidates to compare the best move with its alternatives
+pub const DEFAULT_MULTI_PV: usize = 3;
+
+/// How thoroughly to search each position
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct SearchSettings {
+    pub nodes: u64,
+    pub rollout_depth: u16,
+    pub rollout_temperature: f64,
 }
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
@@ -25,79 +50,159 @@ pub struct Event {
     pub dirichlet_noise: Option<f32>,
     pub rollout_depth: u16,
     pub rollout_temperature: f64,
+    #[serde(default)]
+    pub multi_pv: usize, // How many of the engine's top moves to report as candidates
+    #[serde(default)]
+    pub candidate_pvs: bool, // Whether every candidate needs its full pv, which may take extra searching
 }
 
 #[derive(Debug, Default, PartialEq, Clone, Serialize, Deserialize)]
 pub struct Output {
     pub pv: Vec<String>,
+    // Winning probability for the player who made the last move, which is how the Lambda function reports it
+    // `AnalysisBackend::analyze` flips it to white's perspective
     pub score: f32,
     pub nodes: u64,
     pub mem_usage: u64,
     pub time_taken: Duration,
+    #[serde(default)]
+    pub candidates: Vec<Candidate>, // The engine's top moves, best first. May be empty
 }
 
-pub async fn pv_aws(
-    size: usize,
-    tps: Option<String>,
-    moves: Vec<String>,
-    nodes: u64,
-    rollout_depth: u16,
-    komi: Komi,
-    eval_komi: Komi,
-) -> io::Result<Output> {
-    let is_white = moves.len() % 2 != 1;
-    let event = Event {
-        size,
-        tps,
-        moves,
-        time_control: TimeControl::FixedNodes(nodes),
-        komi: komi.into(),
-        eval_komi: Some(eval_komi.into()),
-        dirichlet_noise: None,
-        rollout_depth,
-        rollout_temperature: 0.2,
-    };
-    let client = LambdaClient::new(Region::UsEast2);
-
-    let request = InvocationRequest {
-        client_context: None,
-        function_name: AWS_FUNCTION_NAME.get().unwrap().clone(),
-        invocation_type: Some("RequestResponse".to_string()),
-        log_type: None,
-        payload: Some(Bytes::copy_from_slice(&serde_json::to_vec(&event).unwrap())),
-        qualifier: None,
-    };
-
-    let result = client.invoke(request).await;
-    match result {
-        Ok(response) => {
-            if let Some(status_code) = response.status_code {
-                if status_code / 100 == 2 {
-                    debug!("Got HTTP response {} from aws", status_code);
+#[derive(Debug, Default, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Candidate {
+    pub mv: String,
+    pub score: f32,
+    #[serde(default)]
+    pub visits: u64, // Zero if the engine doesn't report it
+    #[serde(default)]
+    pub pv: Vec<String>, // Starts with the candidate move. May only contain the move itself
+}
+
+impl Output {
+    /// Flip all scores to the other player's perspective
+    pub fn flip_scores(&mut self) {
+        self.score = 1.0 - self.score;
+        for candidate in self.candidates.iter_mut() {
+            candidate.score = 1.0 - candidate.score;
+        }
+    }
+}
+
+impl Event {
+    pub fn new(
+        size: usize,
+        tps: Option<String>,
+        moves: Vec<String>,
+        settings: SearchSettings,
+        komi: Komi,
+        eval_komi: Komi,
+    ) -> Self {
+        Event {
+            size,
+            tps,
+            moves,
+            time_control: TimeControl::FixedNodes(settings.nodes),
+            komi: komi.into(),
+            eval_komi: Some(eval_komi.into()),
+            dirichlet_noise: None,
+            rollout_depth: settings.rollout_depth,
+            rollout_temperature: settings.rollout_temperature,
+            multi_pv: DEFAULT_MULTI_PV,
+            candidate_pvs: false,
+        }
+    }
+
+    /// Whether white is to move after all the moves have been played
+    pub fn white_to_move(&self) -> bool {
+        let white_to_move_at_start = self
+            .tps
+            .as_ref()
+            .and_then(|tps| tps.split_whitespace().nth(1))
+            != Some("2");
+        white_to_move_at_start == (self.moves.len() % 2 == 0)
+    }
+}
+
+#[cfg(test)]
+impl Event {
+    /// A small search at 0 komi, for tests
+    pub fn for_test(size: usize, tps: Option<&str>, moves: &[&str]) -> Self {
+        let settings = SearchSettings {
+            nodes: 1000,
+            rollout_depth: 0,
+            rollout_temperature: 0.25,
+        };
+        let komi = Komi::from_half_komi(0).unwrap();
+        let moves = moves.iter().map(|mv| mv.to_string()).collect();
+        Event::new(size, tps.map(str::to_string), moves, settings, komi, komi)
+    }
+}
+
+pub struct LambdaBackend {
+    client: LambdaClient,
+    function_name: String,
+}
+
+impl LambdaBackend {
+    pub fn new(function_name: String) -> Self {
+        LambdaBackend {
+            client: LambdaClient::new(Region::UsEast2),
+            function_name,
+        }
+    }
+}
+
+// Only these sizes are known to work with the Lambda function
+pub const REMOTE_SIZES: RangeInclusive<usize> = 4..=6;
+
+#[async_trait]
+impl AnalysisBackend for LambdaBackend {
+    async fn search(&self, event: Event) -> io::Result<Output> {
+        let request = InvocationRequest {
+            client_context: None,
+            function_name: self.function_name.clone(),
+            invocation_type: Some("RequestResponse".to_string()),
+            log_type: None,
+            payload: Some(Bytes::copy_from_slice(&serde_json::to_vec(&event).unwrap())),
+            qualifier: None,
+        };
+
+        let result = self.client.invoke(request).await;
+        match result {
+            Ok(response) => {
+                if let Some(status_code) = response.status_code {
+                    if status_code / 100 == 2 {
+                        debug!("Got HTTP response {} from aws", status_code);
+                    } else {
+                        error!("Got HTTP response {} from aws", status_code);
+                    }
                 } else {
-                    error!("Got HTTP response {} from aws", status_code);
+                    warn!("AWS response contained no status code");
                 }
-            } else {
-                warn!("AWS response contained no status code");
-            }
-            if let Some(payload) = response.payload {
-                let payload_string = std::str::from_utf8(&payload)
-                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
-                debug!("AWS event: {:?}", event);
-                debug!("AWS payload: {}", payload_string);
-                let mut output: Output = serde_json::from_str(payload_string)?;
-                // Always show score from white's perspective
-                if is_white {
-                    output.score = 1.0 - output.score;
+                if let Some(payload) = response.payload {
+                    let payload_string = std::str::from_utf8(&payload)
+                        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                    debug!("AWS event: {:?}", event);
+                    debug!("AWS payload: {}", payload_string);
+                    Ok(serde_json::from_str(payload_string)?)
+                } else {
+                    Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "AWS response contained no payload",
+                    ))
                 }
-                Ok(output)
-            } else {
-                Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    "AWS response contained no payload",
-                ))
             }
+            Err(err) => Err(io::Error::new(io::ErrorKind::Other, err)),
         }
-        Err(err) => Err(io::Error::new(io::ErrorKind::Other, err)),
+    }
+
+    fn supported_sizes(&self) -> RangeInclusive<usize> {
+        REMOTE_SIZES
+    }
+
+    // The Lambda function only knows the fixed node and clock time controls
+    fn supports_move_time(&self) -> bool {
+        false
     }
 }
@@ -0,0 +1,131 @@
+use crate::aws::{self, Event, Output};
+use crate::cli::BackendOptions;
+use crate::http;
+use crate::local;
+use crate::ptn_tags;
+use crate::tei;
+use serenity::async_trait;
+use std::io;
+use std::ops::RangeInclusive;
+
+/// Something that can search a single position and report the engine's evaluation
+#[async_trait]
+pub trait AnalysisBackend: Send + Sync {
+    /// Search the position, with the scores as the engine reports them (see `Output::score`)
+    async fn search(&self, event: Event) -> io::Result<Output>;
+
+    /// Search the position, with the scores from white's perspective
+    async fn analyze(&self, event: Event) -> io::Result<Output> {
+        let white_to_move = event.white_to_move();
+        let mut output = self.search(event).await?;
+        if white_to_move {
+            output.flip_scores();
+        }
+        Ok(output)
+    }
+
+    /// How many searches can run at the same time, or `None` if there is no limit
+    fn parallel_searches(&self) -> Option<usize> {
+        None
+    }
+
+    /// Whether the engine can search for a fixed time, with `TimeControl::MoveTime`
+    fn supports_move_time(&self) -> bool {
+        true
+    }
+
+    /// Board sizes the engine can search
+    fn supported_sizes(&self) -> RangeInclusive<usize> {
+        ptn_tags::MIN_SIZE..=ptn_tags::MAX_SIZE
+    }
+}
+
+pub fn create_backend(options: &BackendOptions) -> io::Result<Box<dyn AnalysisBackend>> {
+    Ok(match options {
+        BackendOptions::Aws { function_name } => {
+            Box::new(aws::LambdaBackend::new(function_name.clone()))
+        }
+        BackendOptions::Local { threads } => Box::new(local::LocalBackend::new(*threads)),
+        BackendOptions::Tei {
+            engine_path,
+            engine_args,
+            max_engines,
+        } => Box::new(tei::TeiBackend::new(
+            engine_path.clone(),
+            engine_args.clone(),
+            *max_engines,
+        )),
+        BackendOptions::Http {
+            url,
+            timeout,
+            retries,
+            max_concurrent_requests,
+        } => Box::new(http::HttpBackend::new(
+            url.clone(),
+            *timeout,
+            *retries,
+            *max_concurrent_requests,
+        )?),
+    })
+}
+
+/// Returns canned outputs instead of searching, in the order they were given
+#[cfg(test)]
+pub struct FakeBackend {
+    outputs: std::sync::Mutex<std::collections::VecDeque<Output>>,
+}
+
+#[cfg(test)]
+impl FakeBackend {
+    pub fn new(outputs: Vec<Output>) -> Self {
+        FakeBackend {
+            outputs: std::sync::Mutex::new(outputs.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl AnalysisBackend for FakeBackend {
+    async fn search(&self, _event: Event) -> io::Result<Output> {
+        self.outputs
+            .lock()
+            .unwrap()
+            .pop_front()
+            .ok_or_else(|| io::Error::other("No more canned outputs"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(moves: &[&str]) -> Event {
+        Event::for_test(6, None, moves)
+    }
+
+    fn output(score: f32) -> Output {
+        Output {
+            score,
+            ..Output::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn fake_backend_returns_outputs_in_order() {
+        let outputs = vec![output(0.25), output(0.75)];
+        let backend: Box<dyn AnalysisBackend> = Box::new(FakeBackend::new(outputs.clone()));
+        assert_eq!(backend.search(event(&[])).await.unwrap(), outputs[0]);
+        assert_eq!(backend.search(event(&[])).await.unwrap(), outputs[1]);
+        assert!(backend.search(event(&[])).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn scores_are_from_whites_perspective() {
+        // White is winning, which the engine reports as a low score for black when white is to move,
+        // and as a high score for white when black is to move
+        let backend = FakeBackend::new(vec![output(0.1), output(0.9)]);
+        assert!(backend.analyze(event(&[])).await.unwrap().score > 0.5);
+        assert!(backend.analyze(event(&["a1"])).await.unwrap().score > 0.5);
+    }
+}
@@ -0,0 +1,295 @@
+use crate::drawing;
+use board_game_traits::Color;
+use std::io;
+use tiltak::position::{Position, Square};
+use tiny_skia::{ColorU8, FillRule, PathBuilder, Pixmap, Rect, Stroke, Transform};
+
+const SQUARE_SIZE: f32 = 72.0;
+const MARGIN: f32 = 28.0;
+const LAYER_HEIGHT: f32 = 4.0;
+// Only draw this many pieces below the top stone. Taller stacks get a height label instead
+const MAX_VISIBLE_LAYERS: usize = 8;
+
+const BACKGROUND: ColorU8 = ColorU8::from_rgba(0x40, 0x40, 0x40, 255);
+const LIGHT_SQUARE: ColorU8 = ColorU8::from_rgba(0x9a, 0x8f, 0x80, 255);
+const DARK_SQUARE: ColorU8 = ColorU8::from_rgba(0x8a, 0x7f, 0x70, 255);
+const HIGHLIGHT: ColorU8 = ColorU8::from_rgba(0xd8, 0xb4, 0x4a, 255);
+const WHITE_PIECE: ColorU8 = ColorU8::from_rgba(0xee, 0xee, 0xee, 255);
+const BLACK_PIECE: ColorU8 = ColorU8::from_rgba(0x30, 0x30, 0x30, 255);
+const OUTLINE: ColorU8 = ColorU8::from_rgba(0x10, 0x10, 0x10, 255);
+const TEXT: ColorU8 = ColorU8::from_rgba(0xdd, 0xdd, 0xdd, 255);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Role {
+    Flat,
+    Wall,
+    Cap,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Stack {
+    // Piece colors from the bottom up, `true` for white
+    colors: Vec<bool>,
+    top_role: Role,
+}
+
+/// The stacks of the board, in rows from the top rank down, like Tiltak numbers its ranks
+fn board_rows<const S: usize>(position: &Position<S>) -> Vec<Vec<Option<Stack>>> {
+    (0..S)
+        .map(|rank| {
+            (0..S)
+                .map(|file| {
+                    let stack = &position[Square::from_rank_file(rank as u8, file as u8)];
+                    let top_stone = stack.top_stone()?;
+                    Some(Stack {
+                        colors: (0..stack.len())
+                            .filter_map(|i| stack.get(i))
+                            .map(|piece| piece.color() == Color::White)
+                            .collect(),
+                        top_role: match top_stone.role() {
+                            tiltak::position::Role::Flat => Role::Flat,
+                            tiltak::position::Role::Wall => Role::Wall,
+                            tiltak::position::Role::Cap => Role::Cap,
+                        },
+                    })
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// The squares touched by a ptn move, as (file, rank) pairs counted from a1
+fn move_squares(mv: &str, size: usize) -> Vec<(usize, usize)> {
+    let mv = mv.trim_start_matches(|ch: char| ch.is_ascii_digit() || "FSC".contains(ch));
+    let mut chars = mv.chars();
+    let (Some(file), Some(rank)) = (chars.next(), chars.next()) else {
+        return vec![];
+    };
+    let (Some(file), Some(rank)) = (
+        (file as usize).checked_sub('a' as usize),
+        rank.to_digit(10)
+            .and_then(|rank| (rank as usize).checked_sub(1)),
+    ) else {
+        return vec![];
+    };
+
+    let direction: (isize, isize) = match chars.next() {
+        Some('+') => (0, 1),
+        Some('-') => (0, -1),
+        Some('>') => (1, 0),
+        Some('<') => (-1, 0),
+        _ => return vec![(file, rank)],
+    };
+    let distance = chars.take_while(char::is_ascii_digit).count().max(1);
+
+    (0..=distance)
+        .map(|i| {
+            (
+                file as isize + direction.0 * i as isize,
+                rank as isize + direction.1 * i as isize,
+            )
+        })
+        .filter(|&(file, rank)| file >= 0 && rank >= 0)
+        .map(|(file, rank)| (file as usize, rank as usize))
+        .filter(|&(file, rank)| file < size && rank < size)
+        .collect()
+}
+
+fn fill_rect(pixmap: &mut Pixmap, x: f32, y: f32, width: f32, height: f32, color: ColorU8) {
+    pixmap.fill_rect(
+        Rect::from_xywh(x, y, width, height).unwrap(),
+        &drawing::paint(color),
+        Transform::identity(),
+        None,
+    );
+}
+
+fn draw_piece(pixmap: &mut Pixmap, role: Role, is_white: bool, center_x: f32, bottom_y: f32) {
+    let path = match role {
+        Role::Flat => {
+            let size = SQUARE_SIZE * 0.4;
+            PathBuilder::from_rect(
+                Rect::from_xywh(center_x - size / 2.0, bottom_y - size, size, size).unwrap(),
+            )
+        }
+        Role::Wall => {
+            let (width, height) = (SQUARE_SIZE * 0.15, SQUARE_SIZE * 0.4);
+            PathBuilder::from_rect(
+                Rect::from_xywh(center_x - width / 2.0, bottom_y - height, width, height).unwrap(),
+            )
+        }
+        Role::Cap => {
+            let radius = SQUARE_SIZE * 0.18;
+            PathBuilder::from_circle(center_x, bottom_y - radius, radius).unwrap()
+        }
+    };
+
+    let paint = drawing::paint(if is_white { WHITE_PIECE } else { BLACK_PIECE });
+    pixmap.fill_path(
+        &path,
+        &paint,
+        FillRule::Winding,
+        Transform::identity(),
+        None,
+    );
+
+    let paint = drawing::paint(OUTLINE);
+    let stroke = Stroke {
+        width: 1.5,
+        ..Stroke::default()
+    };
+    pixmap.stroke_path(&path, &paint, &stroke, Transform::identity(), None);
+}
+
+fn draw_stack(pixmap: &mut Pixmap, stack: &Stack, x: f32, y: f32) {
+    let center_x = x + SQUARE_SIZE / 2.0;
+    let mut bottom_y = y + SQUARE_SIZE * 0.9;
+
+    let (top_color, lower_colors) = stack.colors.split_last().unwrap();
+    let visible_layers = &lower_colors[lower_colors.len().saturating_sub(MAX_VISIBLE_LAYERS)..];
+    for &is_white in visible_layers {
+        let width = SQUARE_SIZE * 0.6;
+        fill_rect(
+            pixmap,
+            center_x - width / 2.0,
+            bottom_y - LAYER_HEIGHT,
+            width,
+            LAYER_HEIGHT,
+            OUTLINE,
+        );
+        fill_rect(
+            pixmap,
+            center_x - width / 2.0 + 1.0,
+            bottom_y - LAYER_HEIGHT + 1.0,
+            width - 2.0,
+            LAYER_HEIGHT - 2.0,
+            if is_white { WHITE_PIECE } else { BLACK_PIECE },
+        );
+        bottom_y -= LAYER_HEIGHT;
+    }
+    // Keep the top stone inside its square
+    let min_bottom_y = y + SQUARE_SIZE * 0.45;
+    draw_piece(
+        pixmap,
+        stack.top_role,
+        *top_color,
+        center_x,
+        bottom_y.max(min_bottom_y),
+    );
+
+    if stack.colors.len() > MAX_VISIBLE_LAYERS + 1 {
+        let label = stack.colors.len().to_string();
+        let label_x = x + SQUARE_SIZE - 3.0 - drawing::text_width(&label, 1.5);
+        drawing::draw_text(pixmap, &label, label_x, y + 3.0, 1.5, OUTLINE);
+    }
+}
+
+/// Render a position as a png image, optionally highlighting the squares of the last move
+pub fn render_board<const S: usize>(
+    position: &Position<S>,
+    last_move: Option<&str>,
+) -> Result<Vec<u8>, io::Error> {
+    let rows = board_rows(position);
+    let size = S;
+
+    let image_size = (SQUARE_SIZE * size as f32 + 2.0 * MARGIN) as u32;
+    let mut pixmap = Pixmap::new(image_size, image_size)
+        .ok_or_else(|| io::Error::other("Failed to allocate image"))?;
+    pixmap.fill(drawing::color(BACKGROUND));
+
+    let highlighted = last_move.map_or(vec![], |mv| move_squares(mv, size));
+
+    for (row_index, row) in rows.iter().enumerate() {
+        let rank = size - 1 - row_index;
+        for (file, square) in row.iter().enumerate() {
+            let x = MARGIN + file as f32 * SQUARE_SIZE;
+            let y = MARGIN + row_index as f32 * SQUARE_SIZE;
+            let square_color = if highlighted.contains(&(file, rank)) {
+                HIGHLIGHT
+            } else if (file + rank) % 2 == 0 {
+                DARK_SQUARE
+            } else {
+                LIGHT_SQUARE
+            };
+            fill_rect(&mut pixmap, x, y, SQUARE_SIZE, SQUARE_SIZE, square_color);
+            fill_rect(&mut pixmap, x, y, SQUARE_SIZE, 1.0, OUTLINE);
+            fill_rect(&mut pixmap, x, y, 1.0, SQUARE_SIZE, OUTLINE);
+
+            if let Some(stack) = square {
+                draw_stack(&mut pixmap, stack, x, y);
+            }
+        }
+    }
+
+    // Coordinates
+    for i in 0..size {
+        let file_label = ((b'a' + i as u8) as char).to_string();
+        let center = MARGIN + (i as f32 + 0.5) * SQUARE_SIZE;
+        drawing::draw_text_centered(
+            &mut pixmap,
+            &file_label,
+            center,
+            MARGIN + size as f32 * SQUARE_SIZE + MARGIN / 2.0,
+            2.0,
+            TEXT,
+        );
+        drawing::draw_text_centered(
+            &mut pixmap,
+            &(size - i).to_string(),
+            MARGIN / 2.0,
+            center,
+            2.0,
+            TEXT,
+        );
+    }
+
+    pixmap.encode_png().map_err(io::Error::other)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pgn_traits::PgnPosition;
+
+    #[test]
+    fn rows_start_at_the_top_rank() {
+        let position = <Position<5>>::from_fen("x5/x5/x5/x5/12,x,2S,1C,x 1 5").unwrap();
+        let rows = board_rows(&position);
+        assert!(rows[..4].iter().flatten().all(Option::is_none));
+        assert_eq!(
+            rows[4],
+            vec![
+                Some(Stack {
+                    colors: vec![true, false],
+                    top_role: Role::Flat
+                }),
+                None,
+                Some(Stack {
+                    colors: vec![false],
+                    top_role: Role::Wall
+                }),
+                Some(Stack {
+                    colors: vec![true],
+                    top_role: Role::Cap
+                }),
+                None,
+            ]
+        );
+    }
+
+    #[test]
+    fn move_squares_follow_spreads() {
+        assert_eq!(move_squares("c3", 6), [(2, 2)]);
+        assert_eq!(move_squares("Sa1", 6), [(0, 0)]);
+        assert_eq!(move_squares("3b2>12", 6), [(1, 1), (2, 1), (3, 1)]);
+        // Squares off the board are left out
+        assert_eq!(move_squares("a1-", 6), [(0, 0)]);
+    }
+
+    #[test]
+    fn renders_png() {
+        let position = <Position<6>>::start_position();
+        let image = render_board(&position, Some("a1")).unwrap();
+        assert!(image.starts_with(b"\x89PNG"));
+    }
+}
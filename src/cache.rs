@@ -0,0 +1,244 @@
+use crate::aws::{Event, Output, TimeControl};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::SystemTime;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CacheEntry {
+    key: String,
+    output: Output,
+}
+
+/// On-disk cache of search results, with one file per searched position
+/// Only fixed node searches are cached, since the result of a timed search depends on the hardware
+pub struct AnalysisCache {
+    directory: PathBuf,
+    // Searches by different engines give different results, so they are cached separately
+    engine_id: String,
+    // When there are more entries than this, the oldest ones are removed
+    max_entries: usize,
+    // Approximate number of entries on disk. Overwritten entries are counted twice until the next eviction
+    entries: AtomicUsize,
+    evicting: AtomicBool,
+    next_temp_id: AtomicU64,
+}
+
+impl AnalysisCache {
+    pub fn new(directory: PathBuf, engine_id: String, max_entries: usize) -> io::Result<Self> {
+        std::fs::create_dir_all(&directory)?;
+        let entries = entry_files(&directory)?.len();
+        Ok(AnalysisCache {
+            directory,
+            engine_id,
+            max_entries,
+            entries: AtomicUsize::new(entries),
+            evicting: AtomicBool::new(false),
+            next_temp_id: AtomicU64::new(0),
+        })
+    }
+
+    pub async fn get(&self, event: &Event) -> Option<Output> {
+        let key = cache_key(event, &self.engine_id)?;
+        let contents = match tokio::fs::read(self.path(&key)).await {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return None,
+            Err(err) => {
+                warn!("Failed to read cache entry: {}", err);
+                return None;
+            }
+        };
+        match serde_json::from_slice::<CacheEntry>(&contents) {
+            // The file name is only a hash, so check that the entry is for the same search
+            Ok(entry) if entry.key == key => Some(entry.output),
+            Ok(_) => None,
+            Err(err) => {
+                warn!("Corrupt cache entry: {}", err);
+                None
+            }
+        }
+    }
+
+    pub async fn insert(&self, event: &Event, output: &Output) {
+        let Some(key) = cache_key(event, &self.engine_id) else {
+            return;
+        };
+        let path = self.path(&key);
+        let entry = CacheEntry {
+            key,
+            output: output.clone(),
+        };
+        // Concurrent writers, even from other processes, each use their own temporary file
+        let temp_path = path.with_extension(format!(
+            "{}.{}.tmp",
+            std::process::id(),
+            self.next_temp_id.fetch_add(1, Ordering::Relaxed)
+        ));
+        let result = match serde_json::to_vec(&entry) {
+            Ok(contents) => write_atomically(&path, &temp_path, &contents).await,
+            Err(err) => Err(err.into()),
+        };
+        if let Err(err) = result {
+            warn!("Failed to write cache entry: {}", err);
+            return;
+        }
+        if self.entries.fetch_add(1, Ordering::Relaxed) >= self.max_entries {
+            self.evict().await;
+        }
+    }
+
+    /// Remove the least recently written entries, leaving some room below the limit
+    async fn evict(&self) {
+        if self.evicting.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let directory = self.directory.clone();
+        let target = self.max_entries - self.max_entries / 10;
+        match tokio::task::spawn_blocking(move || remove_oldest_entries(&directory, target)).await {
+            Ok(Ok(remaining)) => self.entries.store(remaining, Ordering::Relaxed),
+            Ok(Err(err)) => warn!("Failed to evict cache entries: {}", err),
+            Err(err) => warn!("Failed to evict cache entries: {}", err),
+        }
+        self.evicting.store(false, Ordering::Relaxed);
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.directory.join(format!("{:016x}.json", fnv_hash(key)))
+    }
+}
+
+/// Write to a temporary file first, so that a crash never leaves a half-written file
+pub async fn write_atomically(path: &Path, temp_path: &Path, contents: &[u8]) -> io::Result<()> {
+    let result = async {
+        tokio::fs::write(temp_path, contents).await?;
+        tokio::fs::rename(temp_path, path).await
+    }
+    .await;
+    if result.is_err() {
+        if let Err(err) = tokio::fs::remove_file(temp_path).await {
+            if err.kind() != io::ErrorKind::NotFound {
+                warn!("Failed to remove temporary file: {}", err);
+            }
+        }
+    }
+    result
+}
+
+/// The cache entries in the directory, with the time they were written
+fn entry_files(directory: &Path) -> io::Result<Vec<(SystemTime, PathBuf)>> {
+    let mut files = vec![];
+    for dir_entry in std::fs::read_dir(directory)? {
+        let dir_entry = dir_entry?;
+        let path = dir_entry.path();
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "json")
+        {
+            files.push((dir_entry.metadata()?.modified()?, path));
+        }
+    }
+    Ok(files)
+}
+
+/// Remove the oldest entries until at most `max_entries` are left. Returns how many are left
+fn remove_oldest_entries(directory: &Path, max_entries: usize) -> io::Result<usize> {
+    let mut files = entry_files(directory)?;
+    if files.len() <= max_entries {
+        return Ok(files.len());
+    }
+    files.sort();
+    let excess = files.len() - max_entries;
+    for (_, path) in files.drain(..excess) {
+        match std::fs::remove_file(&path) {
+            Ok(()) => (),
+            // Another process may have evicted it already
+            Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(files.len())
+}
+
+/// Everything that determines the search result: engine, size, start position, moves, node count, rollout settings, komis and candidate count
+fn cache_key(event: &Event, engine_id: &str) -> Option<String> {
+    if event.dirichlet_noise.is_some() {
+        return None;
+    }
+    let TimeControl::FixedNodes(nodes) = event.time_control else {
+        return None;
+    };
+    Some(format!(
+        "{}|{}s|{}|{}|nodes={}|depth={}|temp={}|komi={}|eval_komi={:?}|multi_pv={}",
+        engine_id,
+        event.size,
+        event.tps.as_deref().unwrap_or("startpos"),
+        event.moves.join(" "),
+        nodes,
+        event.rollout_depth,
+        event.rollout_temperature,
+        event.komi,
+        event.eval_komi,
+        event.multi_pv,
+    ))
+}
+
+// FNV-1a, which unlike the standard library's hasher is stable between Rust versions
+fn fnv_hash(key: &str) -> u64 {
+    key.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn event(moves: &[&str]) -> Event {
+        Event::for_test(6, None, moves)
+    }
+
+    #[test]
+    fn keys_cover_the_search() {
+        let key = cache_key(&event(&["a1", "f6"]), "local").unwrap();
+        assert_eq!(
+            key,
+            "local|6s|startpos|a1 f6|nodes=1000|depth=0|temp=0.25|komi=0|eval_komi=Some(0.0)|multi_pv=3"
+        );
+        assert_ne!(cache_key(&event(&["a1", "f6"]), "tei:tiltak").unwrap(), key);
+        assert_ne!(cache_key(&event(&["a1", "f5"]), "local").unwrap(), key);
+    }
+
+    #[test]
+    fn random_and_timed_searches_are_not_cached() {
+        let mut noisy = event(&[]);
+        noisy.dirichlet_noise = Some(0.25);
+        assert_eq!(cache_key(&noisy, "local"), None);
+
+        let mut timed = event(&[]);
+        timed.time_control = TimeControl::Time(Duration::from_secs(60), Duration::ZERO);
+        assert_eq!(cache_key(&timed, "local"), None);
+    }
+
+    #[tokio::test]
+    async fn oldest_entries_are_evicted() {
+        let directory =
+            std::env::temp_dir().join(format!("analysis_cache_test_{}", std::process::id()));
+        let cache = AnalysisCache::new(directory.clone(), "fake".to_string(), 2).unwrap();
+        let output = Output {
+            score: 0.75,
+            ..Output::default()
+        };
+        for mv in ["a1", "b1", "c1", "d1"].iter() {
+            cache.insert(&event(&[mv]), &output).await;
+            // Make sure the entries have different modification times
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(entry_files(&directory).unwrap().len() <= 2);
+        assert_eq!(cache.get(&event(&["d1"])).await, Some(output));
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
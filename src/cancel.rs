@@ -0,0 +1,133 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+#[derive(Debug)]
+struct ActiveAnalysis {
+    id: u64,
+    requester_id: u64,
+    channel_id: u64,
+    status_message_id: u64,
+    cancel: Arc<Notify>,
+}
+
+/// Info about a running analysis, for checking who may cancel it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AnalysisInfo {
+    pub id: u64,
+    pub requester_id: u64,
+}
+
+/// All analyses that are currently running, so that they can be cancelled
+pub struct ActiveAnalyses {
+    // Oldest first
+    analyses: Mutex<Vec<ActiveAnalysis>>,
+    next_id: AtomicU64,
+}
+
+impl ActiveAnalyses {
+    pub const fn new() -> Self {
+        ActiveAnalyses {
+            analyses: Mutex::new(Vec::new()),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Register a running analysis. It is unregistered when the handle is dropped
+    pub fn register(
+        &self,
+        requester_id: u64,
+        channel_id: u64,
+        status_message_id: u64,
+    ) -> CancelHandle<'_> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let cancel = Arc::new(Notify::new());
+        self.analyses.lock().unwrap().push(ActiveAnalysis {
+            id,
+            requester_id,
+            channel_id,
+            status_message_id,
+            cancel: cancel.clone(),
+        });
+        CancelHandle {
+            active: self,
+            id,
+            cancel,
+        }
+    }
+
+    /// The analysis whose progress is shown in the given message
+    pub fn by_status_message(&self, status_message_id: u64) -> Option<AnalysisInfo> {
+        self.analyses
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|analysis| analysis.status_message_id == status_message_id)
+            .map(ActiveAnalysis::info)
+    }
+
+    /// The most recently started analysis in the channel, optionally only from one requester
+    pub fn latest_in_channel(
+        &self,
+        channel_id: u64,
+        requester_id: Option<u64>,
+    ) -> Option<AnalysisInfo> {
+        self.analyses
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .filter(|analysis| analysis.channel_id == channel_id)
+            .find(|analysis| requester_id.map_or(true, |id| analysis.requester_id == id))
+            .map(ActiveAnalysis::info)
+    }
+
+    /// Cancel the analysis. Returns false if it has already finished
+    pub fn cancel(&self, id: u64) -> bool {
+        match self
+            .analyses
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|analysis| analysis.id == id)
+        {
+            Some(analysis) => {
+                analysis.cancel.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl ActiveAnalysis {
+    fn info(&self) -> AnalysisInfo {
+        AnalysisInfo {
+            id: self.id,
+            requester_id: self.requester_id,
+        }
+    }
+}
+
+pub struct CancelHandle<'a> {
+    active: &'a ActiveAnalyses,
+    id: u64,
+    cancel: Arc<Notify>,
+}
+
+impl CancelHandle<'_> {
+    /// Wait until someone cancels the analysis
+    pub async fn cancelled(&self) {
+        self.cancel.notified().await
+    }
+}
+
+impl Drop for CancelHandle<'_> {
+    fn drop(&mut self) {
+        self.active
+            .analyses
+            .lock()
+            .unwrap()
+            .retain(|analysis| analysis.id != self.id);
+    }
+}
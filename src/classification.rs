@@ -0,0 +1,316 @@
+// Classifying moves by how much they changed the evaluation, and scoring each player's accuracy
+
+use crate::aws::Output;
+use std::io;
+use std::str::FromStr;
+
+// Win probabilities are clamped to this range before converting them to evals,
+// so that moves in positions that are already decided aren't punished
+const MIN_WIN_PROBABILITY: f32 = 0.01;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MoveQuality {
+    Brilliant,
+    Good,
+    Normal,
+    Inaccuracy,
+    Mistake,
+    Blunder,
+}
+
+impl MoveQuality {
+    /// The move's PTN annotation, which is empty for normal moves
+    pub fn annotation(self) -> &'static str {
+        match self {
+            MoveQuality::Brilliant => "!!",
+            MoveQuality::Good => "!",
+            MoveQuality::Normal => "",
+            MoveQuality::Inaccuracy => "?!",
+            MoveQuality::Mistake => "?",
+            MoveQuality::Blunder => "??",
+        }
+    }
+}
+
+/// Thresholds for classifying moves, in eval units
+/// An eval of 400 means the player is about 10 times as likely to win as to lose, similar to centipawns in chess
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Thresholds {
+    // How much better than the engine's second choice a move must be, if it was the top choice
+    pub brilliant: f32,
+    pub good: f32,
+    // How much eval a move must lose
+    pub inaccuracy: f32,
+    pub mistake: f32,
+    pub blunder: f32,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Thresholds {
+            brilliant: 40.0,
+            good: 20.0,
+            inaccuracy: 40.0,
+            mistake: 80.0,
+            blunder: 180.0,
+        }
+    }
+}
+
+impl FromStr for Thresholds {
+    type Err = io::Error;
+
+    /// Parse thresholds such as `mistake=100,blunder=200`. Thresholds that aren't given keep their default
+    fn from_str(s: &str) -> io::Result<Self> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+        let mut thresholds = Thresholds::default();
+        for part in s.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+            let (name, value) = part
+                .split_once('=')
+                .ok_or_else(|| invalid(format!("Expected NAME=VALUE, got \"{part}\"")))?;
+            let value: f32 = value
+                .parse()
+                .ok()
+                .filter(|value: &f32| value.is_finite() && *value >= 0.0)
+                .ok_or_else(|| invalid(format!("Invalid threshold \"{value}\"")))?;
+            match name {
+                "brilliant" => thresholds.brilliant = value,
+                "good" => thresholds.good = value,
+                "inaccuracy" => thresholds.inaccuracy = value,
+                "mistake" => thresholds.mistake = value,
+                "blunder" => thresholds.blunder = value,
+                _ => return Err(invalid(format!("Unknown threshold \"{name}\""))),
+            }
+        }
+        if !(thresholds.inaccuracy < thresholds.mistake && thresholds.mistake < thresholds.blunder)
+        {
+            return Err(invalid(
+                "Thresholds must increase from inaccuracy to mistake to blunder".to_string(),
+            ));
+        }
+        if thresholds.good > thresholds.brilliant {
+            return Err(invalid(
+                "The good threshold can't be higher than the brilliant threshold".to_string(),
+            ));
+        }
+        Ok(thresholds)
+    }
+}
+
+/// Convert a win probability to an eval on a logistic scale
+/// Equal steps in eval are much larger steps in probability near 50% than near 0% or 100%
+pub fn eval(win_probability: f32) -> f32 {
+    let p = win_probability.clamp(MIN_WIN_PROBABILITY, 1.0 - MIN_WIN_PROBABILITY);
+    400.0 * (p / (1.0 - p)).log10()
+}
+
+/// How much each move changed the eval, from the perspective of the player who made it
+pub fn eval_changes(move_scores: &[f32], white_moves_first: bool) -> Vec<f32> {
+    mover_scores(move_scores, white_moves_first)
+        .map(|(before, after)| eval(after) - eval(before))
+        .collect()
+}
+
+/// How much better the played move was than the engine's second choice, in eval units
+/// `None` if it wasn't the engine's top choice, or the search didn't report any alternatives
+pub fn best_move_margin(output: &Output, played_move: &str, white_to_move: bool) -> Option<f32> {
+    let [best, second_best, ..] = output.candidates.as_slice() else {
+        return None;
+    };
+    if best.mv != played_move {
+        return None;
+    }
+    let margin = eval(best.score) - eval(second_best.score);
+    Some(if white_to_move { margin } else { -margin })
+}
+
+/// Losing eval makes a move an inaccuracy, mistake or blunder
+/// Only the engine's top choice can be good or brilliant, if the alternatives are clearly worse,
+/// since the eval also jumps when the opponent's previous move was bad
+/// Backends that don't report candidate moves never produce good or brilliant moves
+pub fn classify_moves(
+    move_scores: &[f32],
+    best_move_margins: &[Option<f32>],
+    thresholds: &Thresholds,
+    white_moves_first: bool,
+) -> Vec<MoveQuality> {
+    eval_changes(move_scores, white_moves_first)
+        .into_iter()
+        .zip(best_move_margins)
+        .map(|(change, margin)| {
+            if -change >= thresholds.blunder {
+                MoveQuality::Blunder
+            } else if -change >= thresholds.mistake {
+                MoveQuality::Mistake
+            } else if -change >= thresholds.inaccuracy {
+                MoveQuality::Inaccuracy
+            } else {
+                match margin {
+                    Some(margin) if *margin >= thresholds.brilliant => MoveQuality::Brilliant,
+                    Some(margin) if *margin >= thresholds.good => MoveQuality::Good,
+                    _ => MoveQuality::Normal,
+                }
+            }
+        })
+        .collect()
+}
+
+/// How well one player played a game
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PlayerSummary {
+    pub accuracy: f32,
+    pub inaccuracies: usize,
+    pub mistakes: usize,
+    pub blunders: usize,
+}
+
+/// Summaries for white and black, in that order
+/// Accuracy uses Lichess' formula on the drop in win percentage, averaged over the player's moves
+pub fn player_summaries(
+    move_scores: &[f32],
+    qualities: &[MoveQuality],
+    white_moves_first: bool,
+) -> [PlayerSummary; 2] {
+    let accuracies: Vec<f32> = mover_scores(move_scores, white_moves_first)
+        .map(|(before, after)| {
+            let win_percent_loss = (before - after).max(0.0) * 100.0;
+            (103.1668 * (-0.04354 * win_percent_loss).exp() - 3.1669).clamp(0.0, 100.0)
+        })
+        .collect();
+
+    let summary = |first_ply: usize| {
+        let plies = || (first_ply..qualities.len()).step_by(2);
+        let count = |quality| plies().filter(|&ply| qualities[ply] == quality).count();
+        let moves = plies().count();
+        PlayerSummary {
+            accuracy: if moves == 0 {
+                100.0
+            } else {
+                plies().map(|ply| accuracies[ply]).sum::<f32>() / moves as f32
+            },
+            inaccuracies: count(MoveQuality::Inaccuracy),
+            mistakes: count(MoveQuality::Mistake),
+            blunders: count(MoveQuality::Blunder),
+        }
+    };
+    if white_moves_first {
+        [summary(0), summary(1)]
+    } else {
+        [summary(1), summary(0)]
+    }
+}
+
+/// Whether white made the move at this ply
+/// `white_moves_first` is false when the game starts from a position with black to move
+pub fn is_white_move(ply: usize, white_moves_first: bool) -> bool {
+    (ply % 2 == 0) == white_moves_first
+}
+
+/// The scores before and after each move, from the perspective of the player who made it
+fn mover_scores(
+    move_scores: &[f32],
+    white_moves_first: bool,
+) -> impl Iterator<Item = (f32, f32)> + '_ {
+    move_scores
+        .windows(2)
+        .enumerate()
+        .map(move |(ply, scores)| {
+            if is_white_move(ply, white_moves_first) {
+                (scores[0], scores[1])
+            } else {
+                (1.0 - scores[0], 1.0 - scores[1])
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evals() {
+        assert_eq!(eval(0.5), 0.0);
+        assert!((eval(10.0 / 11.0) - 400.0).abs() < 0.1);
+        assert!((eval(0.3) + eval(0.7)).abs() < 0.01);
+        // Decided positions are clamped
+        assert_eq!(eval(1.0), eval(0.99));
+        assert_eq!(eval(0.0), eval(0.01));
+    }
+
+    #[test]
+    fn moves_lose_eval_for_the_player_who_made_them() {
+        let thresholds = Thresholds::default();
+        // White blunders, then black makes an inaccuracy
+        let move_scores = [0.5, 0.1, 0.13];
+        assert_eq!(
+            classify_moves(&move_scores, &[None, None], &thresholds, true),
+            [MoveQuality::Blunder, MoveQuality::Inaccuracy]
+        );
+        // With black moving first, the same scores are good moves for black and bad ones for white
+        assert_eq!(
+            classify_moves(&move_scores, &[None, None], &thresholds, false),
+            [MoveQuality::Normal, MoveQuality::Normal]
+        );
+    }
+
+    #[test]
+    fn only_clear_best_moves_are_good() {
+        let thresholds = Thresholds::default();
+        let move_scores = [0.5, 0.5, 0.5, 0.5];
+        assert_eq!(
+            classify_moves(
+                &move_scores,
+                &[Some(50.0), Some(25.0), Some(5.0)],
+                &thresholds,
+                true
+            ),
+            [
+                MoveQuality::Brilliant,
+                MoveQuality::Good,
+                MoveQuality::Normal
+            ]
+        );
+    }
+
+    #[test]
+    fn summaries_are_split_by_player() {
+        let move_scores = [0.5, 0.5, 0.9, 0.9];
+        let qualities = [
+            MoveQuality::Normal,
+            MoveQuality::Blunder,
+            MoveQuality::Normal,
+        ];
+        let [white, black] = player_summaries(&move_scores, &qualities, true);
+        assert_eq!(white.blunders, 0);
+        assert!(white.accuracy > 99.0);
+        assert_eq!(black.blunders, 1);
+        assert!(black.accuracy < white.accuracy);
+
+        // Black made the first and last moves, and white the blunder
+        let [white, black] = player_summaries(&move_scores, &qualities, false);
+        assert_eq!(white.blunders, 1);
+        assert_eq!(black.blunders, 0);
+        assert!(black.accuracy > 99.9);
+    }
+
+    #[test]
+    fn parse_thresholds() {
+        assert_eq!(
+            "mistake=100, blunder=200".parse::<Thresholds>().unwrap(),
+            Thresholds {
+                mistake: 100.0,
+                blunder: 200.0,
+                ..Thresholds::default()
+            }
+        );
+        assert_eq!("".parse::<Thresholds>().unwrap(), Thresholds::default());
+        assert!("mistake".parse::<Thresholds>().is_err());
+        assert!("mistake=-5".parse::<Thresholds>().is_err());
+        assert!("terrible=500".parse::<Thresholds>().is_err());
+        // Out of order
+        assert!("inaccuracy=100".parse::<Thresholds>().is_err());
+        assert!("mistake=180".parse::<Thresholds>().is_err());
+        assert!("good=50".parse::<Thresholds>().is_err());
+        assert!("good=40".parse::<Thresholds>().is_ok());
+    }
+}
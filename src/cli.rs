@@ -1,10 +1,60 @@
+use crate::classification::Thresholds;
+use crate::rate_limit::RateLimits;
 use clap::{App, Arg};
+use std::collections::HashMap;
 use std::io;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
 
 #[derive(Debug, Clone)]
 pub struct CliOptions {
-    pub aws_function_name: String,
+    pub backend: BackendOptions,
     pub discord_token: String,
+    pub rate_limits: RateLimits,
+    pub cache_dir: PathBuf,
+    pub cache_max_entries: usize,
+    pub guild_defaults_file: PathBuf,
+    // Higher node count limits for members with these role ids
+    pub role_max_nodes: HashMap<u64, u64>,
+    pub move_thresholds: Thresholds,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BackendOptions {
+    Aws {
+        function_name: String,
+    },
+    Local {
+        threads: usize,
+    },
+    Tei {
+        engine_path: String,
+        engine_args: Vec<String>,
+        max_engines: usize,
+    },
+    Http {
+        url: String,
+        timeout: Duration,
+        retries: u32,
+        max_concurrent_requests: usize,
+    },
+}
+
+impl BackendOptions {
+    /// Identifies the engine that runs the searches, so that their cached results aren't mixed up with other engines'
+    pub fn engine_id(&self) -> String {
+        match self {
+            BackendOptions::Aws { function_name } => format!("aws:{function_name}"),
+            BackendOptions::Local { .. } => "local".to_string(),
+            BackendOptions::Tei {
+                engine_path,
+                engine_args,
+                ..
+            } => format!("tei:{engine_path} {}", engine_args.join(" ")),
+            BackendOptions::Http { url, .. } => format!("http:{url}"),
+        }
+    }
 }
 
 pub fn parse_cli_options() -> io::Result<CliOptions> {
@@ -19,13 +69,141 @@ pub fn parse_cli_options() -> io::Result<CliOptions> {
                 .help("Name of debug logfile")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("backend")
+                .long("backend")
+                .help("Where to run the engine searches. The aws and http backends only analyze 4s to 6s")
+                .possible_values(&["aws", "local", "tei", "http"])
+                .default_value("aws")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("aws-function-name")
                 .long("aws-function-name")
-                .required(true)
+                .required_if("backend", "aws")
                 .help("Name of the aws function")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("local-threads")
+                .long("local-threads")
+                .help("Maximum number of simultaneous searches with the local backend. Defaults to the number of cores")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tei-engine")
+                .long("tei-engine")
+                .required_if("backend", "tei")
+                .help("Path to the TEI engine executable")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tei-engine-args")
+                .long("tei-engine-args")
+                .help("Arguments to pass to the TEI engine")
+                .takes_value(true)
+                .multiple(true)
+                .allow_hyphen_values(true),
+        )
+        .arg(
+            Arg::with_name("tei-engines")
+                .long("tei-engines")
+                .help("Maximum number of TEI engine processes to keep running. Defaults to the number of cores")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("http-url")
+                .long("http-url")
+                .required_if("backend", "http")
+                .help("URL of the analysis endpoint. Accepts the same JSON as the aws function")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("http-timeout")
+                .long("http-timeout")
+                .help("Timeout in seconds for each analysis request")
+                .default_value("120")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("http-retries")
+                .long("http-retries")
+                .help("Number of times to retry a failed analysis request")
+                .default_value("2")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("http-max-requests")
+                .long("http-max-requests")
+                .help("Maximum number of simultaneous analysis requests")
+                .default_value("32")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("rate-limit-window")
+                .long("rate-limit-window")
+                .help("Length of the rate limiting window, in minutes")
+                .default_value("60")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("rate-limit-global")
+                .long("rate-limit-global")
+                .help("Maximum number of games analyzed within the window")
+                .default_value("100")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("rate-limit-guild")
+                .long("rate-limit-guild")
+                .help("Maximum number of games analyzed within the window, per server")
+                .default_value("50")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("rate-limit-user")
+                .long("rate-limit-user")
+                .help("Maximum number of games analyzed within the window, per user")
+                .default_value("10")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("cache-dir")
+                .long("cache-dir")
+                .help("Directory for caching search results between runs")
+                .default_value("analysis_cache")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("cache-max-entries")
+                .long("cache-max-entries")
+                .help("Maximum number of cached search results. The oldest ones are removed first")
+                .default_value("1000000")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("guild-defaults-file")
+                .long("guild-defaults-file")
+                .help("File for storing each server's default search settings")
+                .default_value("guild_defaults.json")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("role-max-nodes")
+                .long("role-max-nodes")
+                .value_name("ROLE_ID=NODES")
+                .help("Allow members with the role to search up to this many nodes per position")
+                .takes_value(true)
+                .multiple(true),
+        )
+        .arg(
+            Arg::with_name("move-thresholds")
+                .long("move-thresholds")
+                .value_name("NAME=VALUE,...")
+                .help("Eval thresholds for classifying moves, such as mistake=80,blunder=180. The names are brilliant, good, inaccuracy, mistake and blunder. Moves are only marked brilliant or good if the engine reports at least two candidate moves, which the aws and http backends only do if the remote engine supports multi_pv")
+                .default_value("")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("discord-token")
                 .long("discord-token")
@@ -67,8 +245,80 @@ pub fn parse_cli_options() -> io::Result<CliOptions> {
             .unwrap()
     }
 
+    let backend = match matches.value_of("backend").unwrap() {
+        "aws" => BackendOptions::Aws {
+            function_name: matches.value_of("aws-function-name").unwrap().to_string(),
+        },
+        "local" => BackendOptions::Local {
+            threads: parse_thread_count(matches.value_of("local-threads"))?,
+        },
+        "tei" => BackendOptions::Tei {
+            engine_path: matches.value_of("tei-engine").unwrap().to_string(),
+            engine_args: matches
+                .values_of("tei-engine-args")
+                .map(|values| values.map(str::to_string).collect())
+                .unwrap_or_default(),
+            max_engines: parse_thread_count(matches.value_of("tei-engines"))?,
+        },
+        "http" => BackendOptions::Http {
+            url: matches.value_of("http-url").unwrap().to_string(),
+            timeout: Duration::from_secs(parse_number(matches.value_of("http-timeout").unwrap())?),
+            retries: parse_number(matches.value_of("http-retries").unwrap())?,
+            max_concurrent_requests: parse_number(matches.value_of("http-max-requests").unwrap())?,
+        },
+        _ => unreachable!(),
+    };
+
+    let rate_limits = RateLimits {
+        window: Duration::from_secs(
+            60 * parse_number::<u64>(matches.value_of("rate-limit-window").unwrap())?,
+        ),
+        global: parse_number(matches.value_of("rate-limit-global").unwrap())?,
+        per_guild: parse_number(matches.value_of("rate-limit-guild").unwrap())?,
+        per_user: parse_number(matches.value_of("rate-limit-user").unwrap())?,
+    };
+
+    let role_max_nodes = matches
+        .values_of("role-max-nodes")
+        .into_iter()
+        .flatten()
+        .map(|value| {
+            let (role_id, nodes) = value.split_once('=').ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Expected ROLE_ID=NODES, got \"{value}\""),
+                )
+            })?;
+            Ok((parse_number(role_id)?, parse_number(nodes)?))
+        })
+        .collect::<io::Result<HashMap<u64, u64>>>()?;
+
     Ok(CliOptions {
-        aws_function_name: matches.value_of("aws-function-name").unwrap().to_string(),
+        backend,
+        rate_limits,
+        cache_dir: PathBuf::from(matches.value_of("cache-dir").unwrap()),
+        cache_max_entries: parse_number(matches.value_of("cache-max-entries").unwrap())?,
+        guild_defaults_file: PathBuf::from(matches.value_of("guild-defaults-file").unwrap()),
+        role_max_nodes,
+        move_thresholds: matches.value_of("move-thresholds").unwrap().parse()?,
         discord_token: matches.value_of("discord-token").unwrap().to_string(),
     })
 }
+
+fn parse_number<T>(value: &str) -> io::Result<T>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    value
+        .parse()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+}
+
+/// Parse a thread or process count, defaulting to the number of cores
+fn parse_thread_count(value: Option<&str>) -> io::Result<usize> {
+    match value {
+        Some(count) => parse_number(count),
+        None => Ok(std::thread::available_parallelism()?.get()),
+    }
+}
@@ -1,33 +1,184 @@
-use std::{
-    io::{self, Write},
-    mem,
-    process::{Command, ExitStatus, Stdio},
-};
-
-// Use external python program to render a pretty graph of the game's eval
-// Slightly modified version of the rendering code from WilemBot https://github.com/ViliamVadocz/tak/blob/main/graph.py
-pub fn generate_graph(ptn: &[u8]) -> Result<Vec<u8>, io::Error> {
-    let mut child = Command::new("python3")
-        .arg("graph.py")
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .spawn()?;
-
-    let mut stdin = child
-        .stdin
-        .take()
-        .ok_or(io::Error::other("Failed to open stdin"))?;
-
-    stdin.write_all(ptn)?;
-    mem::drop(stdin); // Close stdin file handle
-
-    let output = child.wait_with_output()?;
-    if !ExitStatus::success(&output.status) {
-        Err(io::Error::other(format!(
-            "Got exit status {}",
-            output.status
-        )))
-    } else {
-        Ok(output.stdout)
+use crate::drawing;
+use std::io;
+use tiny_skia::{ColorU8, PathBuilder, Pixmap, Rect, Stroke, Transform};
+
+// Render a pretty graph of the game's eval
+// Mimics the look of the rendering code from WilemBot https://github.com/ViliamVadocz/tak/blob/main/graph.py
+
+const FIGURE_BACKGROUND: ColorU8 = ColorU8::from_rgba(0xff, 0xff, 0xff, 0xff);
+const BACKGROUND: ColorU8 = ColorU8::from_rgba(0x40, 0x40, 0x40, 0xff);
+const EVALUATION: ColorU8 = ColorU8::from_rgba(0xfb, 0x8b, 0x24, 0xff);
+const MIDDLE: ColorU8 = ColorU8::from_rgba(0x80, 0x80, 0x80, 0xff);
+const WHITE: ColorU8 = ColorU8::from_rgba(0xff, 0xff, 0xff, 0xff);
+const BLACK: ColorU8 = ColorU8::from_rgba(0x00, 0x00, 0x00, 0xff);
+const BLUNDER: ColorU8 = ColorU8::from_rgba(0xe0, 0x30, 0x30, 0xff);
+const TEXT: ColorU8 = ColorU8::from_rgba(0x00, 0x00, 0x00, 0xff);
+
+const WIDTH_PER_PLY: f32 = 16.0;
+const MIN_PLOT_WIDTH: f32 = 400.0;
+const PLOT_HEIGHT: f32 = 400.0;
+const LEFT_MARGIN: f32 = 80.0;
+const RIGHT_MARGIN: f32 = 24.0;
+const TOP_MARGIN: f32 = 48.0;
+const BOTTOM_MARGIN: f32 = 64.0;
+const TEXT_SCALE: f32 = 2.0;
+
+/// Render the scores after each ply, from white's perspective, as a png image
+/// Plies in `blunders` are marked on the graph
+/// `white_moves_first` is false when the game starts from a position with black to move
+pub fn generate_graph(
+    scores: &[f32],
+    title: &str,
+    blunders: &[usize],
+    white_moves_first: bool,
+) -> Result<Vec<u8>, io::Error> {
+    let plies = scores.len();
+    let plot_width = (plies as f32 * WIDTH_PER_PLY).max(MIN_PLOT_WIDTH);
+    let ply_width = plot_width / plies.max(1) as f32;
+
+    let mut pixmap = Pixmap::new(
+        (LEFT_MARGIN + plot_width + RIGHT_MARGIN) as u32,
+        (TOP_MARGIN + PLOT_HEIGHT + BOTTOM_MARGIN) as u32,
+    )
+    .ok_or_else(|| io::Error::other("Failed to allocate image"))?;
+    pixmap.fill(drawing::color(FIGURE_BACKGROUND));
+
+    let fill_rect = |pixmap: &mut Pixmap, x: f32, y: f32, width: f32, height: f32, color| {
+        if let Some(rect) = Rect::from_xywh(x, y, width, height) {
+            pixmap.fill_rect(rect, &drawing::paint(color), Transform::identity(), None);
+        }
+    };
+    let x_of_ply = |ply: usize| LEFT_MARGIN + ply as f32 * ply_width;
+    let y_of_score = |score: f32| TOP_MARGIN + (1.0 - score.clamp(0.0, 1.0)) * PLOT_HEIGHT;
+    let middle_y = y_of_score(0.5);
+
+    fill_rect(
+        &mut pixmap,
+        LEFT_MARGIN,
+        TOP_MARGIN,
+        plot_width,
+        PLOT_HEIGHT,
+        BACKGROUND,
+    );
+
+    // Fill the area between the eval and 50%, with the color of the player who is ahead
+    for (ply, &score) in scores.iter().enumerate() {
+        let score_y = y_of_score(score);
+        let (color, top) = if score < 0.5 {
+            (BLACK, middle_y)
+        } else {
+            (WHITE, score_y)
+        };
+        fill_rect(
+            &mut pixmap,
+            x_of_ply(ply),
+            top,
+            ply_width,
+            (score_y - middle_y).abs(),
+            color,
+        );
+    }
+
+    let stroke = Stroke {
+        width: 2.0,
+        ..Stroke::default()
+    };
+
+    let mut middle_line = PathBuilder::new();
+    middle_line.move_to(LEFT_MARGIN, middle_y);
+    middle_line.line_to(LEFT_MARGIN + plot_width, middle_y);
+    if let Some(path) = middle_line.finish() {
+        pixmap.stroke_path(
+            &path,
+            &drawing::paint(MIDDLE),
+            &stroke,
+            Transform::identity(),
+            None,
+        );
     }
+
+    // Step plot of the eval
+    let mut eval_line = PathBuilder::new();
+    for (ply, &score) in scores.iter().enumerate() {
+        if ply == 0 {
+            eval_line.move_to(x_of_ply(ply), y_of_score(score));
+        } else {
+            eval_line.line_to(x_of_ply(ply), y_of_score(score));
+        }
+        eval_line.line_to(x_of_ply(ply + 1), y_of_score(score));
+    }
+    if let Some(path) = eval_line.finish() {
+        pixmap.stroke_path(
+            &path,
+            &drawing::paint(EVALUATION),
+            &stroke,
+            Transform::identity(),
+            None,
+        );
+    }
+
+    for &ply in blunders.iter().filter(|&&ply| ply < plies) {
+        let center_x = x_of_ply(ply) + ply_width / 2.0;
+        if let Some(path) = PathBuilder::from_circle(center_x, y_of_score(scores[ply]), 5.0) {
+            pixmap.fill_path(
+                &path,
+                &drawing::paint(BLUNDER),
+                tiny_skia::FillRule::Winding,
+                Transform::identity(),
+                None,
+            );
+        }
+    }
+
+    // Axes
+    for i in 0..=5 {
+        let score = i as f32 / 5.0;
+        let label = format!("{}%", i * 20);
+        let y = y_of_score(score);
+        fill_rect(&mut pixmap, LEFT_MARGIN - 6.0, y - 1.0, 6.0, 2.0, TEXT);
+        drawing::draw_text(
+            &mut pixmap,
+            &label,
+            LEFT_MARGIN - 10.0 - drawing::text_width(&label, TEXT_SCALE),
+            y - drawing::GLYPH_HEIGHT * TEXT_SCALE / 2.0,
+            TEXT_SCALE,
+            TEXT,
+        );
+    }
+    // Label every move on short games, and fewer on long games so that the labels don't overlap
+    // The labels are at white's moves
+    let move_label_interval = (plies / 2 / 40).max(1);
+    let first_white_ply = if white_moves_first { 0 } else { 1 };
+    for ply in (first_white_ply..plies).step_by(2 * move_label_interval) {
+        let x = x_of_ply(ply);
+        let y = TOP_MARGIN + PLOT_HEIGHT;
+        fill_rect(&mut pixmap, x - 1.0, y, 2.0, 6.0, TEXT);
+        drawing::draw_text_centered(
+            &mut pixmap,
+            &((ply + first_white_ply) / 2 + 1).to_string(),
+            x,
+            y + 16.0,
+            TEXT_SCALE,
+            TEXT,
+        );
+    }
+
+    drawing::draw_text_centered(
+        &mut pixmap,
+        title,
+        LEFT_MARGIN + plot_width / 2.0,
+        TOP_MARGIN / 2.0,
+        TEXT_SCALE,
+        TEXT,
+    );
+    drawing::draw_text_centered(
+        &mut pixmap,
+        "Move Number",
+        LEFT_MARGIN + plot_width / 2.0,
+        TOP_MARGIN + PLOT_HEIGHT + BOTTOM_MARGIN - 18.0,
+        TEXT_SCALE,
+        TEXT,
+    );
+
+    pixmap.encode_png().map_err(io::Error::other)
 }
@@ -0,0 +1,238 @@
+// Summarizing an analyzed game in a Discord embed
+
+use crate::aws::Output;
+use crate::classification::{self, PlayerSummary};
+use serenity::builder::CreateEmbed;
+
+const KEY_MOMENTS_SHOWN: usize = 3;
+// The game is decided once the winner's win probability stays above this until the end
+const DECISIVE_WIN_PROBABILITY: f32 = 0.9;
+
+/// One of the biggest mistakes of the game
+#[derive(Debug, PartialEq)]
+pub struct KeyMoment {
+    pub move_name: String,
+    // Scores for white before and after the move
+    pub score_before: f32,
+    pub score_after: f32,
+    // The engine's preferred move, if it wasn't the move that was played
+    pub better_move: Option<String>,
+}
+
+/// When the winner of the game stopped being in doubt
+#[derive(Debug, Clone, PartialEq)]
+pub enum Decided<T> {
+    FromStart,
+    After(T), // The deciding ply, or the name of its move
+    Never,
+}
+
+pub struct GameSummary {
+    pub white_name: String,
+    pub black_name: String,
+    pub result: Option<String>,
+    pub time_control: Option<String>,
+    // White and black, in that order
+    pub players: [PlayerSummary; 2],
+    pub key_moments: Vec<KeyMoment>,
+    pub decided: Decided<String>,
+}
+
+impl GameSummary {
+    pub fn embed(&self) -> CreateEmbed {
+        let mut embed = CreateEmbed::default();
+        embed.title(format!("{} vs {}", self.white_name, self.black_name));
+
+        let mut description = vec![];
+        if let Some(result) = &self.result {
+            description.push(format!("Result: {result}"));
+        }
+        if let Some(time_control) = &self.time_control {
+            description.push(format!("Time control: {time_control}"));
+        }
+        if !description.is_empty() {
+            embed.description(description.join("\n"));
+        }
+
+        for (name, player) in [&self.white_name, &self.black_name]
+            .iter()
+            .zip(self.players)
+        {
+            embed.field(
+                name,
+                format!(
+                    "{:.1}% accuracy\n{} inaccuracies\n{} mistakes\n{} blunders",
+                    player.accuracy, player.inaccuracies, player.mistakes, player.blunders
+                ),
+                true,
+            );
+        }
+
+        if !self.key_moments.is_empty() {
+            let lines: Vec<String> = self
+                .key_moments
+                .iter()
+                .map(|moment| {
+                    let mut line = format!(
+                        "**{}**: {:.1}% → {:.1}% for white",
+                        moment.move_name,
+                        moment.score_before * 100.0,
+                        moment.score_after * 100.0
+                    );
+                    if let Some(better_move) = &moment.better_move {
+                        line.push_str(&format!(", better was {better_move}"));
+                    }
+                    line
+                })
+                .collect();
+            embed.field("Key moments", lines.join("\n"), false);
+        }
+
+        embed.field(
+            "Decided",
+            match &self.decided {
+                Decided::FromStart => "The winner was clear from the start".to_string(),
+                Decided::After(move_name) => {
+                    format!("The winner was never in doubt after {move_name}")
+                }
+                Decided::Never => "The game was never decided by a clear margin".to_string(),
+            },
+            false,
+        );
+        embed
+    }
+}
+
+/// The moves that lost the most eval for the player who made them, in the order they were played
+/// `played_moves` has the moves of the game, and `outputs` the searches before and after each move
+pub fn key_moments(
+    played_moves: &[String],
+    outputs: &[Output],
+    white_moves_first: bool,
+) -> Vec<KeyMoment> {
+    let move_scores: Vec<f32> = outputs.iter().map(|output| output.score).collect();
+    let mut swings: Vec<(usize, f32)> =
+        classification::eval_changes(&move_scores, white_moves_first)
+            .into_iter()
+            .enumerate()
+            .collect();
+    swings.retain(|(_, change)| *change < 0.0);
+    swings.sort_by(|(_, change1), (_, change2)| change1.total_cmp(change2));
+    swings.truncate(KEY_MOMENTS_SHOWN);
+    swings.sort_by_key(|(ply, _)| *ply);
+
+    swings
+        .into_iter()
+        .filter_map(|(ply, _)| {
+            let played_move = played_moves.get(ply)?;
+            let better_move = outputs[ply]
+                .pv
+                .first()
+                .filter(|best_move| *best_move != played_move)
+                .cloned();
+            Some(KeyMoment {
+                move_name: move_name(ply, played_move, white_moves_first),
+                score_before: move_scores[ply],
+                score_after: move_scores[ply + 1],
+                better_move,
+            })
+        })
+        .collect()
+}
+
+/// The ply after which the side that is winning at the end stayed clearly winning
+pub fn decisive_ply(move_scores: &[f32]) -> Decided<usize> {
+    let Some(&final_score) = move_scores.last() else {
+        return Decided::Never;
+    };
+    let winning = |score: f32| {
+        if final_score > 0.5 {
+            score >= DECISIVE_WIN_PROBABILITY
+        } else {
+            score <= 1.0 - DECISIVE_WIN_PROBABILITY
+        }
+    };
+    // The position after this ply is the first one of the decided stretch
+    match move_scores.iter().rposition(|&score| !winning(score)) {
+        None => Decided::FromStart,
+        Some(last_undecided) if last_undecided + 1 < move_scores.len() => {
+            Decided::After(last_undecided)
+        }
+        Some(_) => Decided::Never,
+    }
+}
+
+/// A move with its move number, such as `12. c3` for white or `12... c3` for black
+pub fn move_name(ply: usize, move_string: &str, white_moves_first: bool) -> String {
+    // If black moves first, the first move is `1... c3`
+    let ply = if white_moves_first { ply } else { ply + 1 };
+    format!(
+        "{}{} {}",
+        ply / 2 + 1,
+        if ply % 2 == 0 { "." } else { "..." },
+        move_string
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decided_games() {
+        assert_eq!(decisive_ply(&[0.5, 0.6, 0.95, 0.97]), Decided::After(1));
+        assert_eq!(decisive_ply(&[0.5, 0.05, 0.3, 0.02]), Decided::After(2));
+        assert_eq!(decisive_ply(&[0.95, 0.92, 0.99]), Decided::FromStart);
+        assert_eq!(decisive_ply(&[0.5, 0.95, 0.6]), Decided::Never);
+        assert_eq!(decisive_ply(&[]), Decided::Never);
+    }
+
+    #[test]
+    fn key_moments_are_the_biggest_losses() {
+        let played_moves: Vec<String> = ["a1", "b1", "c1", "d1"]
+            .iter()
+            .map(|mv| mv.to_string())
+            .collect();
+        let outputs: Vec<Output> = [0.5, 0.45, 0.8, 0.2, 0.05]
+            .iter()
+            .map(|&score| Output {
+                pv: vec!["b1".to_string()],
+                score,
+                ..Output::default()
+            })
+            .collect();
+        // Black's last move gains more than white's first move loses, but isn't a key moment
+        let moments = key_moments(&played_moves, &outputs, true);
+        assert_eq!(
+            moments,
+            [
+                KeyMoment {
+                    move_name: "1. a1".to_string(),
+                    score_before: 0.5,
+                    score_after: 0.45,
+                    better_move: Some("b1".to_string()),
+                },
+                KeyMoment {
+                    move_name: "1... b1".to_string(),
+                    score_before: 0.45,
+                    score_after: 0.8,
+                    better_move: None,
+                },
+                KeyMoment {
+                    move_name: "2. c1".to_string(),
+                    score_before: 0.8,
+                    score_after: 0.2,
+                    better_move: Some("b1".to_string()),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn move_names() {
+        assert_eq!(move_name(0, "a1", true), "1. a1");
+        assert_eq!(move_name(3, "a1", true), "2... a1");
+        assert_eq!(move_name(0, "a1", false), "1... a1");
+        assert_eq!(move_name(3, "a1", false), "3. a1");
+    }
+}
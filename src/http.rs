@@ -0,0 +1,118 @@
+use crate::aws::{self, Event, Output};
+use crate::backend::AnalysisBackend;
+use log::{debug, warn};
+use serenity::async_trait;
+use std::io;
+use std::ops::RangeInclusive;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+
+const FIRST_RETRY_DELAY: Duration = Duration::from_millis(500);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Posts each `Event` as JSON to an HTTP endpoint, which responds with an `Output`
+/// This is the same schema as the AWS Lambda function, so the function can be self-hosted
+pub struct HttpBackend {
+    client: reqwest::Client,
+    url: String,
+    retries: u32,
+    concurrent_requests: Semaphore,
+    max_concurrent_requests: usize,
+}
+
+impl HttpBackend {
+    pub fn new(
+        url: String,
+        timeout: Duration,
+        retries: u32,
+        max_concurrent_requests: usize,
+    ) -> io::Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(timeout)
+            .build()
+            .map_err(io::Error::other)?;
+        Ok(HttpBackend {
+            client,
+            url,
+            retries,
+            concurrent_requests: Semaphore::new(max_concurrent_requests),
+            max_concurrent_requests,
+        })
+    }
+
+    async fn post(&self, event: &Event) -> Result<Output, reqwest::Error> {
+        self.client
+            .post(&self.url)
+            .json(event)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+    }
+}
+
+#[async_trait]
+impl AnalysisBackend for HttpBackend {
+    async fn search(&self, event: Event) -> io::Result<Output> {
+        let _permit = self
+            .concurrent_requests
+            .acquire()
+            .await
+            .map_err(io::Error::other)?;
+
+        let mut attempt = 0;
+        let output = loop {
+            match self.post(&event).await {
+                Ok(output) => break output,
+                Err(err) => {
+                    // Client errors won't go away by retrying
+                    let is_client_error = err.status().is_some_and(|s| s.is_client_error());
+                    if attempt >= self.retries || is_client_error {
+                        return Err(io::Error::other(err));
+                    }
+                    warn!("Analysis request to {} failed, retrying: {}", self.url, err);
+                    tokio::time::sleep(retry_delay(attempt)).await;
+                    attempt += 1;
+                }
+            }
+        };
+        debug!("HTTP event: {:?}", event);
+        debug!("HTTP output: {:?}", output);
+        Ok(output)
+    }
+
+    fn parallel_searches(&self) -> Option<usize> {
+        Some(self.max_concurrent_requests)
+    }
+
+    // The endpoint runs the Lambda function's code
+    fn supported_sizes(&self) -> RangeInclusive<usize> {
+        aws::REMOTE_SIZES
+    }
+
+    fn supports_move_time(&self) -> bool {
+        false
+    }
+}
+
+/// Exponential backoff, doubling the delay after each attempt
+fn retry_delay(attempt: u32) -> Duration {
+    2u32.checked_pow(attempt)
+        .and_then(|factor| FIRST_RETRY_DELAY.checked_mul(factor))
+        .map_or(MAX_RETRY_DELAY, |delay| delay.min(MAX_RETRY_DELAY))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_delay_is_capped() {
+        assert_eq!(retry_delay(0), FIRST_RETRY_DELAY);
+        assert_eq!(retry_delay(2), FIRST_RETRY_DELAY * 4);
+        assert_eq!(retry_delay(10), MAX_RETRY_DELAY);
+        assert_eq!(retry_delay(40), MAX_RETRY_DELAY);
+        assert_eq!(retry_delay(u32::MAX), MAX_RETRY_DELAY);
+    }
+}
@@ -0,0 +1,260 @@
+use crate::aws::{Candidate, Event, Output, TimeControl};
+use crate::backend::AnalysisBackend;
+use board_game_traits::{Color, GameResult, Position as PositionTrait};
+use pgn_traits::PgnPosition;
+use serenity::async_trait;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use tiltak::position::{Komi, Position};
+use tiltak::search::{MctsSetting, MonteCarloTree};
+use tokio::sync::Semaphore;
+
+/// Runs Tiltak's search in-process, on tokio's blocking thread pool
+pub struct LocalBackend {
+    // Each search uses one thread, and potentially a lot of memory,
+    // so limit how many can run at the same time
+    search_threads: Arc<Semaphore>,
+    threads: usize,
+}
+
+impl LocalBackend {
+    pub fn new(threads: usize) -> Self {
+        LocalBackend {
+            search_threads: Arc::new(Semaphore::new(threads)),
+            threads,
+        }
+    }
+}
+
+#[async_trait]
+impl AnalysisBackend for LocalBackend {
+    async fn search(&self, event: Event) -> io::Result<Output> {
+        let permit = self
+            .search_threads
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(io::Error::other)?;
+        // The blocking thread can't be aborted, so tell it to stop if this future is dropped
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let _cancel_on_drop = CancelOnDrop(cancelled.clone());
+        tokio::task::spawn_blocking(move || {
+            // Only give the thread back once the search has actually stopped
+            let _permit = permit;
+            match event.size {
+                3 => search::<3>(&event, &cancelled),
+                4 => search::<4>(&event, &cancelled),
+                5 => search::<5>(&event, &cancelled),
+                6 => search::<6>(&event, &cancelled),
+                7 => search::<7>(&event, &cancelled),
+                8 => search::<8>(&event, &cancelled),
+                s => Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Size {s} is not supported"),
+                )),
+            }
+        })
+        .await
+        .map_err(io::Error::other)?
+    }
+
+    fn parallel_searches(&self) -> Option<usize> {
+        Some(self.threads)
+    }
+}
+
+struct CancelOnDrop(Arc<AtomicBool>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+fn komi_from_f64(komi: f64) -> io::Result<Komi> {
+    Komi::from_half_komi((komi * 2.0).round() as i8)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid komi {komi}")))
+}
+
+fn search<const S: usize>(event: &Event, cancelled: &AtomicBool) -> io::Result<Output> {
+    let start_time = Instant::now();
+
+    let komi = komi_from_f64(event.komi)?;
+    let eval_komi = match event.eval_komi {
+        Some(eval_komi) => komi_from_f64(eval_komi)?,
+        None => komi,
+    };
+
+    let mut position = match &event.tps {
+        Some(tps) => <Position<S>>::from_fen_with_komi(tps, komi)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err.to_string()))?,
+        None => <Position<S>>::start_position_with_komi(komi),
+    };
+    for move_string in event.moves.iter() {
+        let mv = position
+            .move_from_san(move_string)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err.to_string()))?;
+        position.do_move(mv);
+    }
+
+    // The game is already over, so there is nothing to search
+    if let Some(game_result) = position.game_result() {
+        let white_score = match game_result {
+            GameResult::WhiteWin => 1.0,
+            GameResult::BlackWin => 0.0,
+            GameResult::Draw => 0.5,
+        };
+        return Ok(Output {
+            pv: vec![],
+            // Reported like the search's scores, for the player who made the last move
+            score: match position.side_to_move() {
+                Color::White => 1.0 - white_score,
+                Color::Black => white_score,
+            },
+            nodes: 0,
+            mem_usage: 0,
+            time_taken: start_time.elapsed(),
+            candidates: vec![],
+        });
+    }
+
+    let mut settings = MctsSetting::default()
+        .add_value_params(<Position<S>>::value_params(eval_komi).into())
+        .add_policy_params(<Position<S>>::policy_params(eval_komi).into())
+        .add_rollout_depth(event.rollout_depth)
+        .add_rollout_temperature(event.rollout_temperature);
+    if let Some(dirichlet_noise) = event.dirichlet_noise {
+        settings = settings.add_dirichlet(dirichlet_noise);
+    }
+
+    // Kept for searching the candidate moves' lines
+    let root_position = position.clone();
+    let root_settings = settings.clone();
+
+    let tree = match event.time_control {
+        TimeControl::FixedNodes(nodes) => {
+            let mut tree = MonteCarloTree::with_settings(
+                position,
+                settings.arena_size_for_nodes(nodes as u32),
+            );
+            for _ in 0..nodes {
+                if cancelled.load(Ordering::Relaxed) || tree.select().is_none() {
+                    break;
+                }
+            }
+            tree
+        }
+        TimeControl::Time(..) | TimeControl::MoveTime(_) => {
+            let move_time = event.time_control.move_time().unwrap();
+            let mut tree = MonteCarloTree::with_settings(position, settings);
+            while start_time.elapsed() < move_time {
+                if cancelled.load(Ordering::Relaxed) || tree.select().is_none() {
+                    break;
+                }
+            }
+            tree
+        }
+    };
+
+    // Tiltak's tree reports scores for the side to move, while `Output` has the last mover's
+    let (_, score) = tree.best_move();
+    let pv: Vec<String> = tree.pv().map(|mv| mv.to_string()).collect();
+
+    // The tree only has a pv for its best move, so the lines of the other candidates come from
+    // a new search of the position after them, as large as their part of the tree
+    // That can double the search, so it's only done when the lines are shown
+    let candidate_pv = |mv, visits: u64| {
+        let mut position = root_position.clone();
+        position.do_move(mv);
+        let mut line = vec![mv.to_string()];
+        if position.game_result().is_some() {
+            return line;
+        }
+        let mut tree = MonteCarloTree::with_settings(
+            position,
+            root_settings.clone().arena_size_for_nodes(visits as u32),
+        );
+        for _ in 0..visits {
+            if cancelled.load(Ordering::Relaxed) || tree.select().is_none() {
+                break;
+            }
+        }
+        line.extend(tree.pv().map(|mv| mv.to_string()));
+        line
+    };
+
+    let mut children = tree.children();
+    children.sort_by_key(|(_, visits, _)| std::cmp::Reverse(*visits));
+    let candidates = children
+        .into_iter()
+        .take(event.multi_pv)
+        .map(|(mv, visits, score)| {
+            let move_string = mv.to_string();
+            let candidate_pv = if pv.first() == Some(&move_string) {
+                pv.clone()
+            } else if event.candidate_pvs {
+                candidate_pv(mv, visits as u64)
+            } else {
+                vec![move_string.clone()]
+            };
+            Candidate {
+                mv: move_string,
+                score: 1.0 - score,
+                visits: visits as u64,
+                pv: candidate_pv,
+            }
+        })
+        .collect();
+
+    Ok(Output {
+        pv,
+        score: 1.0 - score,
+        nodes: tree.visits() as u64,
+        mem_usage: tree.mem_usage() as u64,
+        time_taken: start_time.elapsed(),
+        candidates,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn event(tps: &str) -> Event {
+        Event::for_test(4, Some(tps), &[])
+    }
+
+    #[tokio::test]
+    async fn dropped_search_gives_back_its_thread() {
+        let backend = LocalBackend::new(1);
+        let mut long_search = event("x4/x4/x4/x4 1 1");
+        long_search.time_control = TimeControl::MoveTime(Duration::from_secs(3600));
+        let result = tokio::time::timeout(Duration::from_millis(100), backend.analyze(long_search));
+        assert!(result.await.is_err());
+
+        // Only one search can run at a time, so this waits until the first one has stopped
+        let finished_game = event("1,1,1,1/2,2,2,x/x4/x4 2 4");
+        let result = tokio::time::timeout(Duration::from_secs(10), backend.analyze(finished_game));
+        assert!(result.await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn white_win_is_above_half() {
+        let backend = LocalBackend::new(1);
+        // White has already made a road
+        let output = backend
+            .analyze(event("1,1,1,1/2,2,2,x/x4/x4 2 4"))
+            .await
+            .unwrap();
+        assert_eq!(output.score, 1.0);
+        // White can make a road in one move
+        let output = backend
+            .analyze(event("1,1,1,x/2,2,2,x/x4/x4 1 4"))
+            .await
+            .unwrap();
+        assert!(output.score > 0.5);
+    }
+}
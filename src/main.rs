@@ -1,9 +1,37 @@
 mod aws;
+mod backend;
+mod board_image;
+mod cache;
+mod cancel;
+mod classification;
 mod cli;
+mod drawing;
 mod eval_graph;
+mod game_summary;
+mod http;
+mod local;
+mod ptn_tags;
+mod queue;
+mod rate_limit;
+mod request;
+mod search_options;
+mod slash_commands;
+mod tei;
+mod time_budget;
 
-use crate::aws::Output;
-use board_game_traits::Position as PositionTrait;
+use crate::aws::{Candidate, Event, Output, SearchSettings, TimeControl};
+use crate::backend::AnalysisBackend;
+use crate::cache::AnalysisCache;
+use crate::cancel::{ActiveAnalyses, AnalysisInfo};
+use crate::classification::{MoveQuality, Thresholds};
+use crate::game_summary::{Decided, GameSummary};
+use crate::queue::{AnalysisQueue, QueuePermit, QueueTicket};
+use crate::rate_limit::{LimitScope, RateLimited, RateLimiter};
+use crate::request::Request;
+use crate::search_options::{parse_duration, GuildDefaults, SearchOverrides};
+use board_game_traits::{Color, Position as PositionTrait};
+use futures::future::{BoxFuture, FutureExt};
+use futures::stream::{FuturesUnordered, StreamExt};
 use log::warn;
 use once_cell::sync::OnceCell;
 use pgn_traits::PgnPosition;
@@ -16,41 +44,178 @@ use serenity::framework::standard::{
     macros::{command, group},
     CommandResult, StandardFramework,
 };
-use serenity::http::Typing;
-use serenity::model::channel::Message;
+use serenity::model::application::interaction::Interaction;
+use serenity::model::channel::{Attachment, Message, Reaction, ReactionType};
+use serenity::model::gateway::Ready;
+use serenity::model::id::{GuildId, UserId};
 use serenity::model::prelude::AttachmentType;
 use serenity::prelude::GatewayIntents;
+use std::collections::HashMap;
+use std::io;
 use std::str::FromStr;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::time;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{self, Duration, SystemTime, UNIX_EPOCH};
 use tiltak::position::{Komi, Position};
 use tiltak::ptn::{Game, PtnMove};
-use tokio::sync::Semaphore;
 
-static AWS_FUNCTION_NAME: OnceCell<String> = OnceCell::new();
+static BACKEND: OnceCell<Box<dyn AnalysisBackend>> = OnceCell::new();
 
-static CURRENTLY_ANALYZING: Semaphore = Semaphore::const_new(2);
+static ANALYSIS_QUEUE: AnalysisQueue = AnalysisQueue::new(2, MAX_QUEUE_LENGTH);
+const MAX_QUEUE_LENGTH: usize = 10;
 
-static GAMES_ANALYZED: AtomicUsize = AtomicUsize::new(0);
-const MAX_GAMES_ANALYZED: usize = 200;
+static RATE_LIMITER: OnceCell<RateLimiter> = OnceCell::new();
+
+static CACHE: OnceCell<AnalysisCache> = OnceCell::new();
+
+static ACTIVE_ANALYSES: ActiveAnalyses = ActiveAnalyses::new();
+const CANCEL_EMOJI: char = '❌';
+
+static GUILD_DEFAULTS: OnceCell<GuildDefaults> = OnceCell::new();
+// Higher node count limits for members with these role ids
+static ROLE_MAX_NODES: OnceCell<HashMap<u64, u64>> = OnceCell::new();
+
+static MOVE_THRESHOLDS: OnceCell<Thresholds> = OnceCell::new();
+
+// `ready` is also called after reconnecting, but the slash commands only need to be registered once
+static SLASH_COMMANDS_REGISTERED: AtomicBool = AtomicBool::new(false);
+
+const MAX_PTN_NODES: u64 = 2_000_000;
+const MAX_PTN_ATTACHMENT_SIZE: u64 = 100_000;
+// Limits for analyzing a game with a total time budget instead of a node count
+const MIN_ANALYSIS_TIME: Duration = Duration::from_secs(1);
+const MAX_ANALYSIS_TIME: Duration = Duration::from_secs(600);
+// Sizes where the engine's evaluation is less well tuned
+const EXPERIMENTAL_SIZES: [usize; 3] = [3, 7, 8];
+const MAX_GAMES_PER_REQUEST: usize = 4;
+// Discord's limit for attachments on one message
+const MAX_ATTACHMENTS: usize = 10;
+const DEFAULT_TPS_NODES: u64 = 2_000_000;
+const MAX_TPS_NODES: u64 = 10_000_000;
+const TPS_CANDIDATES_SHOWN: usize = 5;
+// Candidate moves listed in the annotated game, at critical moments
+const MAX_ALTERNATIVES_SHOWN: usize = 2;
+const MAX_BLUNDER_DIAGRAMS: usize = 2;
+const PROGRESS_UPDATE_INTERVAL: Duration = Duration::from_secs(5);
+
+const DEFAULT_ROLLOUT_TEMPERATURE: f64 = 0.2;
+const DEFAULT_PTN_SEARCH: SearchSettings = SearchSettings {
+    nodes: 1_000_000,
+    rollout_depth: 0,
+    rollout_temperature: DEFAULT_ROLLOUT_TEMPERATURE,
+};
+// Full MCTS rollouts, but a much lower node count
+const SLATEBOT_SEARCH: SearchSettings = SearchSettings {
+    nodes: 100_000,
+    rollout_depth: 1000,
+    rollout_temperature: DEFAULT_ROLLOUT_TEMPERATURE,
+};
+const DEFAULT_TPS_SEARCH: SearchSettings = SearchSettings {
+    nodes: DEFAULT_TPS_NODES,
+    rollout_depth: 0,
+    rollout_temperature: DEFAULT_ROLLOUT_TEMPERATURE,
+};
 
 #[group]
-#[commands(analyze_ptn, analyze_ptn_slatebot, analyze_tps, ping)]
+#[commands(
+    analyze_ptn,
+    analyze_ptn_slatebot,
+    analyze_tps,
+    analysis_defaults,
+    cancel,
+    ping
+)]
 struct General;
 
 struct Handler;
 
 #[async_trait]
-impl EventHandler for Handler {}
+impl EventHandler for Handler {
+    async fn ready(&self, ctx: Context, ready: Ready) {
+        println!("Connected as {}", ready.user.name);
+        if !SLASH_COMMANDS_REGISTERED.swap(true, Ordering::SeqCst) {
+            if let Err(err) = slash_commands::register(&ctx).await {
+                warn!("Failed to register slash commands: {}", err);
+                // Try again on the next reconnect
+                SLASH_COMMANDS_REGISTERED.store(false, Ordering::SeqCst);
+            }
+        }
+    }
+
+    async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
+        if let Interaction::ApplicationCommand(command) = interaction {
+            if let Err(err) = slash_commands::handle(&ctx, &command).await {
+                warn!("Error in /{}: {}", command.data.name, err);
+            }
+        }
+    }
+
+    // Reacting to an analysis' progress message cancels it
+    async fn reaction_add(&self, ctx: Context, reaction: Reaction) {
+        if reaction.emoji != ReactionType::Unicode(CANCEL_EMOJI.to_string()) {
+            return;
+        }
+        let (Some(user_id), Some(guild_id)) = (reaction.user_id, reaction.guild_id) else {
+            return;
+        };
+        if user_id == ctx.cache.current_user_id() {
+            return;
+        }
+        let Some(analysis) = ACTIVE_ANALYSES.by_status_message(reaction.message_id.0) else {
+            return;
+        };
+        if may_cancel(&ctx, guild_id, user_id, analysis).await {
+            ACTIVE_ANALYSES.cancel(analysis.id);
+        }
+    }
+}
 
 #[tokio::main]
 async fn main() {
     let cli_options = cli::parse_cli_options().unwrap();
     println!("Options: {cli_options:?}");
 
-    AWS_FUNCTION_NAME
-        .set(cli_options.aws_function_name)
-        .unwrap();
+    if BACKEND
+        .set(backend::create_backend(&cli_options.backend).unwrap())
+        .is_err()
+    {
+        panic!("Analysis backend was already initialized");
+    }
+
+    if RATE_LIMITER
+        .set(RateLimiter::new(cli_options.rate_limits))
+        .is_err()
+    {
+        panic!("Rate limiter was already initialized");
+    }
+
+    if CACHE
+        .set(
+            AnalysisCache::new(
+                cli_options.cache_dir,
+                cli_options.backend.engine_id(),
+                cli_options.cache_max_entries,
+            )
+            .unwrap(),
+        )
+        .is_err()
+    {
+        panic!("Analysis cache was already initialized");
+    }
+
+    if GUILD_DEFAULTS
+        .set(GuildDefaults::load(cli_options.guild_defaults_file).unwrap())
+        .is_err()
+    {
+        panic!("Guild defaults were already initialized");
+    }
+
+    if ROLE_MAX_NODES.set(cli_options.role_max_nodes).is_err() {
+        panic!("Role node limits were already initialized");
+    }
+
+    if MOVE_THRESHOLDS.set(cli_options.move_thresholds).is_err() {
+        panic!("Move thresholds were already initialized");
+    }
 
     let framework = StandardFramework::new()
         .configure(|c| c.prefix("!")) // set the bot's prefix to "~"
@@ -79,11 +244,180 @@ async fn main() {
 #[command]
 async fn ping(ctx: &Context, msg: &Message) -> CommandResult {
     println!("Received {} from {}", msg.content, msg.author.name);
-    msg.reply(ctx, "Pong!").await?;
+    let request = Request::from_message(ctx, msg);
+    request.reply("Pong!").await?;
+
+    Ok(())
+}
+
+#[command]
+async fn cancel(ctx: &Context, msg: &Message) -> CommandResult {
+    let request = Request::from_message(ctx, msg);
+    let Some(guild_id) = msg.guild_id else {
+        request
+            .reply("This command only works in a server.")
+            .await?;
+        return Ok(());
+    };
+    // Cancel the user's own analysis first, and otherwise the latest one in the channel
+    let analysis = ACTIVE_ANALYSES
+        .latest_in_channel(msg.channel_id.0, Some(msg.author.id.0))
+        .or_else(|| ACTIVE_ANALYSES.latest_in_channel(msg.channel_id.0, None));
+    let Some(analysis) = analysis else {
+        request
+            .reply("There is no analysis running in this channel.")
+            .await?;
+        return Ok(());
+    };
+    if !may_cancel(ctx, guild_id, msg.author.id, analysis).await {
+        request
+            .reply("Only the person who requested the analysis, or a moderator, can cancel it.")
+            .await?;
+        return Ok(());
+    }
+    if !ACTIVE_ANALYSES.cancel(analysis.id) {
+        request.reply("The analysis has already finished.").await?;
+    }
+    Ok(())
+}
+
+/// Whether the user requested the analysis, or is allowed to manage messages in the guild
+async fn may_cancel(
+    ctx: &Context,
+    guild_id: GuildId,
+    user_id: UserId,
+    analysis: AnalysisInfo,
+) -> bool {
+    if analysis.requester_id == user_id.0 {
+        return true;
+    }
+    match guild_id.member(ctx, user_id).await {
+        Ok(member) => member
+            .permissions(ctx)
+            .is_ok_and(|permissions| permissions.manage_messages()),
+        Err(err) => {
+            warn!("Failed to look up member {}: {}", user_id, err);
+            false
+        }
+    }
+}
+
+/// Show or change the server's default search settings, for example `!analysis_defaults nodes=500k depth=0`
+#[command]
+async fn analysis_defaults(ctx: &Context, msg: &Message) -> CommandResult {
+    println!("Received {} from {}", msg.content, msg.author.name);
+    let request = Request::from_message(ctx, msg);
+    let Some(guild_id) = msg.guild_id else {
+        request
+            .reply("This command only works in a server.")
+            .await?;
+        return Ok(());
+    };
+    let guild_defaults = GUILD_DEFAULTS.get().unwrap();
+    let words: Vec<&str> = msg.content.split_whitespace().skip(1).collect();
+    if words.is_empty() {
+        request
+            .reply(guild_defaults_text(guild_defaults.get(guild_id.0)))
+            .await?;
+        return Ok(());
+    }
+
+    let may_manage_guild = match guild_id.member(ctx, msg.author.id).await {
+        Ok(member) => member
+            .permissions(ctx)
+            .is_ok_and(|permissions| permissions.manage_guild()),
+        Err(err) => {
+            warn!("Failed to look up member {}: {}", msg.author.id, err);
+            false
+        }
+    };
+    if !may_manage_guild {
+        request
+            .reply("Only server admins can change the default search settings.")
+            .await?;
+        return Ok(());
+    }
+
+    let mut overrides = guild_defaults.get(guild_id.0);
+    if words == ["reset"] {
+        overrides = SearchOverrides::default();
+    } else {
+        for word in words {
+            let result = word
+                .split_once('=')
+                .and_then(|(key, value)| overrides.parse_option(key, value));
+            match result {
+                Some(Ok(())) => (),
+                Some(Err(error)) => {
+                    request.reply(error).await?;
+                    return Ok(());
+                }
+                None => {
+                    request
+                        .reply(format!("Unknown option \"{word}\". Usage: !analysis_defaults [nodes=<nodes>] [depth=<rollout depth>] [temperature=<rollout temperature>], or !analysis_defaults reset"))
+                        .await?;
+                    return Ok(());
+                }
+            }
+        }
+    }
+    if overrides
+        .nodes
+        .is_some_and(|nodes| !(1..=MAX_PTN_NODES).contains(&nodes))
+    {
+        request
+            .reply(format!("Node count must be between 1 and {MAX_PTN_NODES}"))
+            .await?;
+        return Ok(());
+    }
 
+    if let Err(err) = guild_defaults.set(guild_id.0, overrides).await {
+        warn!("Failed to save guild defaults: {}", err);
+        request.reply("Failed to save the settings.").await?;
+        return Ok(());
+    }
+    request
+        .reply(format!("New defaults:\n{}", guild_defaults_text(overrides)))
+        .await?;
     Ok(())
 }
 
+/// The guild's defaults for both games and positions
+fn guild_defaults_text(overrides: SearchOverrides) -> String {
+    format!(
+        "Games: {}\nPositions: {}",
+        search_settings_text(overrides.apply_to(DEFAULT_PTN_SEARCH)),
+        search_settings_text(overrides.apply_to(DEFAULT_TPS_SEARCH))
+    )
+}
+
+/// The default search settings, with the guild's overrides applied
+fn guild_search_settings(request: &Request<'_>, defaults: SearchSettings) -> SearchSettings {
+    let guild_id = request.guild_id().map_or(0, |guild_id| guild_id.0);
+    GUILD_DEFAULTS
+        .get()
+        .unwrap()
+        .get(guild_id)
+        .apply_to(defaults)
+}
+
+fn search_settings_text(settings: SearchSettings) -> String {
+    format!(
+        "{} nodes, rollout depth {}, rollout temperature {}",
+        settings.nodes, settings.rollout_depth, settings.rollout_temperature
+    )
+}
+
+/// The highest node count the user may search with, which may be raised by their roles
+fn max_nodes(request: &Request<'_>, base_max: u64) -> u64 {
+    let role_max_nodes = ROLE_MAX_NODES.get().unwrap();
+    request
+        .role_ids()
+        .iter()
+        .filter_map(|role_id| role_max_nodes.get(&role_id.0))
+        .fold(base_max, |max, &nodes| max.max(nodes))
+}
+
 // This command analysis with slatebot, meaning full MCTS rollouts, but a much lower node count
 #[command]
 async fn analyze_ptn_slatebot(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
@@ -93,142 +427,638 @@ async fn analyze_ptn_slatebot(ctx: &Context, msg: &Message, args: Args) -> Comma
 #[command]
 async fn analyze_ptn(ctx: &Context, msg: &Message) -> CommandResult {
     println!("Received {} from {}", msg.content, msg.author.name);
+    let request = Request::from_message(ctx, msg);
     if msg.guild_id.is_none() {
-        msg.reply(ctx, "Analysis is only available in specific channels.")
+        request
+            .reply("Analysis is only available in specific channels.")
             .await?;
         return Ok(());
     }
-    if let Some(game_id) = msg
+    let mut options = PtnOptions {
+        slatebot: msg.content.starts_with("!analyze_ptn_slatebot"),
+        ..PtnOptions::default()
+    };
+    let arguments = msg
         .content
+        .split_once(|ch: char| ch.is_whitespace())
+        .map_or("", |(_, arguments)| arguments);
+    let (mut option_words, ptn_text) = split_options(arguments);
+    // Options may also come after a playtak game id
+    let game_id = ptn_text
         .split_whitespace()
-        .nth(1)
-        .and_then(|word| word.parse::<usize>().ok())
-    {
-        let start_time = time::Instant::now();
-        let Ok(ptn_response) = reqwest::get(format!(
-            "https://api.playtak.com/v1/games-history/ptn/{}",
-            game_id
-        ))
-        .await
-        else {
-            msg.reply(
-                ctx,
-                format!(
-                    "Failed to fetch PTN for game #{} from Playtak server",
-                    game_id
-                ),
-            )
-            .await?;
+        .next()
+        .and_then(|word| word.parse::<u64>().ok());
+    if game_id.is_some() {
+        let after_game_id = ptn_text
+            .trim_start()
+            .split_once(char::is_whitespace)
+            .map_or("", |(_, rest)| rest);
+        let (trailing_options, rest) = split_options(after_game_id);
+        if let Some(word) = rest.split_whitespace().next() {
+            request.reply(format!("Unknown option \"{word}\"")).await?;
             return Ok(());
-        };
-        if ptn_response.status() == StatusCode::NOT_FOUND {
-            msg.reply(
-                ctx,
-                format!(
-                    "Game #{} not found on Playtak. Was the game id correct?",
-                    game_id
-                ),
-            )
+        }
+        option_words.extend(trailing_options);
+    }
+    for (key, value) in option_words {
+        if let Some(result) = options.search.parse_option(key, value) {
+            if let Err(error) = result {
+                request.reply(error).await?;
+                return Ok(());
+            }
+            continue;
+        }
+        match key {
+            "komi" => match Komi::from_str(value) {
+                Ok(komi) => options.komi = Some(komi),
+                Err(_) => {
+                    request
+                        .reply(format!("Couldn't analyze with {value} komi"))
+                        .await?;
+                    return Ok(());
+                }
+            },
+            "eval_komi" => match Komi::from_str(value) {
+                Ok(komi) => options.eval_komi = Some(komi),
+                Err(_) => {
+                    request
+                        .reply(format!("Couldn't evaluate with {value} komi"))
+                        .await?;
+                    return Ok(());
+                }
+            },
+            "time" => match parse_duration(value) {
+                Some(time) => options.time = Some(time),
+                None => {
+                    request
+                        .reply(format!("Couldn't read time \"{value}\""))
+                        .await?;
+                    return Ok(());
+                }
+            },
+            "game" => match value.parse::<usize>() {
+                Ok(number) if number >= 1 => options.game = Some(number),
+                _ => {
+                    request
+                        .reply(format!("Couldn't read game number \"{value}\""))
+                        .await?;
+                    return Ok(());
+                }
+            },
+            _ => {
+                request
+                    .reply(format!("Unknown option \"{key}={value}\""))
+                    .await?;
+                return Ok(());
+            }
+        }
+    }
+
+    if let Some(game_id) = game_id {
+        analyze_playtak_game(&request, game_id, options).await
+    } else if !ptn_text.trim().is_empty() {
+        analyze_ptn_unsized(&request, ptn_text, options).await
+    } else if let Some(attachment) = msg.attachments.first() {
+        analyze_ptn_attachment(&request, attachment, options).await
+    } else if let Some(referenced_message) = msg.referenced_message.as_deref() {
+        // Replying to a message analyzes the PTN in it
+        match referenced_message.attachments.first() {
+            Some(attachment) => analyze_ptn_attachment(&request, attachment, options).await,
+            None if !referenced_message.content.trim().is_empty() => {
+                analyze_ptn_unsized(&request, &referenced_message.content, options).await
+            }
+            None => {
+                request
+                    .reply("The replied-to message doesn't contain any PTN.")
+                    .await?;
+                Ok(())
+            }
+        }
+    } else {
+        request.reply("No PTN provided.").await?;
+        Ok(())
+    }
+}
+
+async fn analyze_ptn_attachment(
+    request: &Request<'_>,
+    attachment: &Attachment,
+    options: PtnOptions,
+) -> CommandResult {
+    match download_ptn_attachment(attachment).await {
+        Ok(ptn_text) => analyze_ptn_unsized(request, &ptn_text, options).await,
+        Err(error) => {
+            request.reply(error).await?;
+            Ok(())
+        }
+    }
+}
+
+/// Download a PTN file, or return an error message for the user
+async fn download_ptn_attachment(attachment: &Attachment) -> Result<String, String> {
+    let filename = attachment.filename.to_lowercase();
+    if !filename.ends_with(".ptn") && !filename.ends_with(".txt") {
+        return Err(format!(
+            "Couldn't read {}. Only .ptn and .txt files are supported.",
+            attachment.filename
+        ));
+    }
+    if attachment.size > MAX_PTN_ATTACHMENT_SIZE {
+        return Err(format!(
+            "{} is too large. PTN files can be at most {}kB.",
+            attachment.filename,
+            MAX_PTN_ATTACHMENT_SIZE / 1000
+        ));
+    }
+    let start_time = time::Instant::now();
+    let bytes = attachment.download().await.map_err(|err| {
+        warn!("Failed to download {}: {}", attachment.url, err);
+        format!("Failed to download {}", attachment.filename)
+    })?;
+    println!(
+        "Downloaded {} in {:.2}s",
+        attachment.filename,
+        start_time.elapsed().as_secs_f32()
+    );
+    String::from_utf8(bytes).map_err(|_| format!("{} is not a text file.", attachment.filename))
+}
+
+/// Split leading options such as `game=2` from the rest of the command
+fn split_options(mut text: &str) -> (Vec<(&str, &str)>, &str) {
+    let mut options = vec![];
+    loop {
+        text = text.trim_start();
+        let word = text.split_whitespace().next().unwrap_or_default();
+        // PTN tags may also contain '=', but they always start with '['
+        match word.split_once('=') {
+            Some(option) if !word.starts_with('[') => {
+                options.push(option);
+                text = &text[word.len()..];
+            }
+            _ => return (options, text),
+        }
+    }
+}
+
+/// Settings for analyzing a game, which the user may override
+#[derive(Debug, Clone, Default)]
+struct PtnOptions {
+    // Full MCTS rollouts, but a much lower node count
+    slatebot: bool,
+    search: SearchOverrides,
+    // Overrides the game's komi tag
+    komi: Option<Komi>,
+    // Komi for the heuristic evaluation, if the user picked one
+    eval_komi: Option<Komi>,
+    // Which game to analyze from a PTN file with several, 1-indexed
+    game: Option<usize>,
+    // Total time to spend on the analysis, instead of a fixed node count per position
+    time: Option<Duration>,
+}
+
+async fn analyze_playtak_game(
+    request: &Request<'_>,
+    game_id: u64,
+    options: PtnOptions,
+) -> CommandResult {
+    let start_time = time::Instant::now();
+    let Ok(ptn_response) = reqwest::get(format!(
+        "https://api.playtak.com/v1/games-history/ptn/{}",
+        game_id
+    ))
+    .await
+    else {
+        request
+            .reply(format!(
+                "Failed to fetch PTN for game #{} from Playtak server",
+                game_id
+            ))
             .await?;
-            return Ok(());
-        } else if !ptn_response.status().is_success() {
-            msg.reply(
-                ctx,
-                format!(
-                    "Error: Got http {} when fetching PTN from Playtak",
-                    ptn_response.status()
-                ),
-            )
+        return Ok(());
+    };
+    if ptn_response.status() == StatusCode::NOT_FOUND {
+        request
+            .reply(format!(
+                "Game #{} not found on Playtak. Was the game id correct?",
+                game_id
+            ))
+            .await?;
+        return Ok(());
+    } else if !ptn_response.status().is_success() {
+        request
+            .reply(format!(
+                "Error: Got http {} when fetching PTN from Playtak",
+                ptn_response.status()
+            ))
+            .await?;
+        return Ok(());
+    }
+    println!(
+        "Fetched ptn from Playtak in {:.2}s",
+        start_time.elapsed().as_secs_f32()
+    );
+    let Ok(ptn_text) = ptn_response.text().await else {
+        request
+            .reply(format!(
+                "Error fetching PTN for game #{} from Playtak server",
+                game_id
+            ))
             .await?;
+        return Ok(());
+    };
+    analyze_ptn_unsized(request, &ptn_text, options).await
+}
+
+async fn analyze_ptn_unsized(
+    request: &Request<'_>,
+    ptn_text: &str,
+    options: PtnOptions,
+) -> CommandResult {
+    let size = match ptn_tags::board_size(ptn_text).and_then(check_backend_size) {
+        Ok(size) => size,
+        Err(error) => {
+            request.reply(error).await?;
             return Ok(());
         }
-        println!(
-            "Fetched ptn from Playtak in {:.2}s",
-            start_time.elapsed().as_secs_f32()
-        );
-        let Ok(ptn_text) = ptn_response.text().await else {
-            msg.reply(
-                ctx,
-                format!(
-                    "Error fetching PTN for game #{} from Playtak server",
-                    game_id
-                ),
-            )
+    };
+    match size {
+        3 => analyze_ptn_sized::<3>(request, ptn_text, options).await,
+        4 => analyze_ptn_sized::<4>(request, ptn_text, options).await,
+        5 => analyze_ptn_sized::<5>(request, ptn_text, options).await,
+        6 => analyze_ptn_sized::<6>(request, ptn_text, options).await,
+        7 => analyze_ptn_sized::<7>(request, ptn_text, options).await,
+        8 => analyze_ptn_sized::<8>(request, ptn_text, options).await,
+        _ => unreachable!(),
+    }
+}
+
+#[command]
+async fn analyze_tps(ctx: &Context, msg: &Message) -> CommandResult {
+    println!("Received {} from {}", msg.content, msg.author.name);
+    let request = Request::from_message(ctx, msg);
+    if msg.guild_id.is_none() {
+        request
+            .reply("Analysis is only available in specific channels.")
             .await?;
+        return Ok(());
+    }
+    let mut words = msg.content.split_whitespace().skip(1);
+    // A tps string is always the board, the side to move and the move number
+    let tps_words: Vec<&str> = words.by_ref().take(3).collect();
+    if tps_words.len() < 3 {
+        request
+            .reply("Couldn't read tps. Usage: !analyze_tps <tps> [komi=<komi>] [eval_komi=<komi>] [nodes=<nodes>] [depth=<rollout depth>] [temperature=<rollout temperature>]")
+            .await?;
+        return Ok(());
+    }
+    let tps = tps_words.join(" ");
+
+    let mut options = TpsOptions::default();
+    for word in words {
+        let Some((key, value)) = word.split_once('=') else {
+            request.reply(format!("Unknown option \"{word}\"")).await?;
             return Ok(());
         };
-        analyze_ptn_unsized(ctx, msg, &ptn_text).await
-    } else if let Some((_, ptn_text)) = msg.content.split_once(|ch: char| ch.is_whitespace()) {
-        analyze_ptn_unsized(ctx, msg, ptn_text).await
-    } else {
-        msg.reply(ctx, "No PTN provided.").await?;
-        Ok(())
+        match key {
+            "komi" => match Komi::from_str(value) {
+                Ok(k) => options.komi = k,
+                Err(_) => {
+                    request
+                        .reply(format!("Couldn't analyze with {value} komi"))
+                        .await?;
+                    return Ok(());
+                }
+            },
+            "eval_komi" => match Komi::from_str(value) {
+                Ok(k) => options.eval_komi = Some(k),
+                Err(_) => {
+                    request
+                        .reply(format!("Couldn't evaluate with {value} komi"))
+                        .await?;
+                    return Ok(());
+                }
+            },
+            _ => match options.search.parse_option(key, value) {
+                Some(Ok(())) => (),
+                Some(Err(error)) => {
+                    request.reply(error).await?;
+                    return Ok(());
+                }
+                None => {
+                    request.reply(format!("Unknown option \"{word}\"")).await?;
+                    return Ok(());
+                }
+            },
+        }
     }
+
+    analyze_tps_unsized(&request, &tps, options).await
 }
 
-async fn analyze_ptn_unsized(ctx: &Context, msg: &Message, ptn_text: &str) -> CommandResult {
-    if let Some(size_line) = ptn_text.lines().find(|line| line.contains("Size")) {
-        match size_line
-            .split_whitespace()
-            .nth(1)
-            .and_then(|r| r.chars().nth(1).and_then(|r| r.to_digit(10)))
-        {
-            Some(4) => analyze_ptn_sized::<4>(ctx, msg, ptn_text).await?,
-            Some(5) => analyze_ptn_sized::<5>(ctx, msg, ptn_text).await?,
-            Some(6) => analyze_ptn_sized::<6>(ctx, msg, ptn_text).await?,
-            Some(s) => {
-                msg.reply(ctx, format!("Size {s} is not supported."))
-                    .await?;
-                return Ok(());
-            }
-            None => {
-                msg.reply(
-                    ctx,
-                    "Invalid Size tag. The PTN must include a tag such as [Size \"6\"].",
-                )
+/// Settings for analyzing a single position, which the user may override
+#[derive(Debug, Clone)]
+struct TpsOptions {
+    komi: Komi,
+    // Komi for the heuristic evaluation, if the user picked one
+    eval_komi: Option<Komi>,
+    search: SearchOverrides,
+}
+
+impl Default for TpsOptions {
+    fn default() -> Self {
+        TpsOptions {
+            komi: Komi::from_half_komi(0).unwrap(),
+            eval_komi: None,
+            search: SearchOverrides::default(),
+        }
+    }
+}
+
+async fn analyze_tps_unsized(
+    request: &Request<'_>,
+    tps: &str,
+    options: TpsOptions,
+) -> CommandResult {
+    let Some(size) = ptn_tags::tps_size(tps) else {
+        request
+            .reply(format!("Couldn't read tps \"{tps}\""))
+            .await?;
+        return Ok(());
+    };
+    if let Err(error) = ptn_tags::check_size(size).and_then(|()| check_backend_size(size)) {
+        request.reply(error).await?;
+        return Ok(());
+    }
+    match size {
+        3 => analyze_tps_sized::<3>(request, tps, options).await,
+        4 => analyze_tps_sized::<4>(request, tps, options).await,
+        5 => analyze_tps_sized::<5>(request, tps, options).await,
+        6 => analyze_tps_sized::<6>(request, tps, options).await,
+        7 => analyze_tps_sized::<7>(request, tps, options).await,
+        8 => analyze_tps_sized::<8>(request, tps, options).await,
+        _ => unreachable!(),
+    }
+}
+
+async fn analyze_tps_sized<const S: usize>(
+    request: &Request<'_>,
+    tps: &str,
+    options: TpsOptions,
+) -> CommandResult {
+    let komi = options.komi;
+    let settings = options
+        .search
+        .apply_to(guild_search_settings(request, DEFAULT_TPS_SEARCH));
+    let max_nodes = max_nodes(request, MAX_TPS_NODES);
+    if !(1..=max_nodes).contains(&settings.nodes) {
+        request
+            .reply(format!("Node count must be between 1 and {max_nodes}"))
+            .await?;
+        return Ok(());
+    }
+
+    let position = match <Position<S>>::from_fen_with_komi(tps, komi) {
+        Ok(position) => position,
+        Err(err) => {
+            request.reply(format!("Couldn't read tps: {err}")).await?;
+            return Ok(());
+        }
+    };
+    if position.game_result().is_some() {
+        request.reply("The game is already over.").await?;
+        return Ok(());
+    }
+
+    let eval_komi = match eval_komi(S, komi, options.eval_komi) {
+        Ok(eval_komi) => eval_komi,
+        Err(error) => {
+            request.reply(error).await?;
+            return Ok(());
+        }
+    };
+    if komi != eval_komi {
+        request
+            .reply(eval_komi_note(
+                S,
+                komi,
+                eval_komi,
+                options.eval_komi.is_some(),
+            ))
+            .await?;
+    }
+
+    if EXPERIMENTAL_SIZES.contains(&S) {
+        request.reply(experimental_size_note(S)).await?;
+    }
+
+    // A position counts against the rate limits like a game, but only if the analysis succeeds
+    let reservation = match RATE_LIMITER.get().unwrap().try_reserve(
+        request.guild_id().map_or(0, |id| id.0),
+        request.author().id.0,
+    ) {
+        Ok(reservation) => reservation,
+        Err(rate_limited) => {
+            request.reply(rate_limited_message(rate_limited)).await?;
+            return Ok(());
+        }
+    };
+
+    let Ok(mut ticket) = ANALYSIS_QUEUE.join() else {
+        request.reply(
+            format!("The analysis queue is full, with {MAX_QUEUE_LENGTH} games waiting. Try again later."),
+        )
+        .await?;
+        return Ok(());
+    };
+    let (permit, queue_message) = wait_in_queue(request, &mut ticket, "position").await?;
+
+    let mut status_message = match queue_message {
+        Some(mut queue_message) => {
+            request
+                .edit_reply(&mut queue_message, "Analyzing...")
                 .await?;
-                return Ok(());
+            queue_message
+        }
+        None => request.reply("Analyzing...").await?,
+    };
+    let cancel_handle = ACTIVE_ANALYSES.register(
+        request.author().id.0,
+        request.channel_id().0,
+        status_message.id.0,
+    );
+    if let Err(err) = status_message.react(request.ctx, CANCEL_EMOJI).await {
+        warn!("Failed to add cancel reaction: {}", err);
+    }
+
+    let typing = request.start_typing()?;
+    let start_time = time::Instant::now();
+
+    let mut event = Event::new(S, Some(tps.to_string()), vec![], settings, komi, eval_komi);
+    event.multi_pv = TPS_CANDIDATES_SHOWN;
+    event.candidate_pvs = true;
+    let result = tokio::select! {
+        result = BACKEND.get().unwrap().analyze(event) => Some(result),
+        _ = cancel_handle.cancelled() => None,
+    };
+    drop(cancel_handle);
+    drop(permit);
+
+    if let Some(typing) = typing {
+        typing.stop();
+    }
+
+    let status_text = match &result {
+        None => "Analysis cancelled.".to_string(),
+        Some(Err(_)) => "Analysis failed.".to_string(),
+        Some(Ok(_)) => format!(
+            "Analyzed the position in {:.1}s.",
+            start_time.elapsed().as_secs_f32()
+        ),
+    };
+    if let Err(err) = request.edit_reply(&mut status_message, status_text).await {
+        warn!("Failed to update progress message: {}", err);
+    }
+
+    match result {
+        None => Ok(()),
+        Some(Err(error)) => {
+            warn!("Analysis error: {}", error);
+            request.reply("Analysis error.").await?;
+            Err("Analysis error".into())
+        }
+        Some(Ok(output)) => {
+            println!(
+                "Analyzed {} in {:.1}s",
+                tps,
+                start_time.elapsed().as_secs_f32()
+            );
+            reservation.commit();
+            let mut reply = format!(
+                "Evaluation: {:.1}% for white after {} nodes\nPV: {}",
+                output.score * 100.0,
+                output.nodes,
+                output.pv.join(" ")
+            );
+            if !output.candidates.is_empty() {
+                reply.push_str(&format!(
+                    "\nCandidates:\n```\n{}```",
+                    candidate_table(&output.candidates)
+                ));
             }
-        };
-        Ok(())
-    } else if let Some(tps_line) = ptn_text.lines().find(|line| line.contains("TPS")) {
-        match tps_line.chars().filter(|ch| *ch == '/').count() + 1 {
-            4 => analyze_ptn_sized::<4>(ctx, msg, ptn_text).await?,
-            5 => analyze_ptn_sized::<5>(ctx, msg, ptn_text).await?,
-            6 => analyze_ptn_sized::<6>(ctx, msg, ptn_text).await?,
-            s => {
-                msg.reply(ctx, format!("Size {s} is unsupported.")).await?;
-                return Ok(());
+            let mut files = vec![];
+            match board_image::render_board(&position, None) {
+                Ok(board_image) => files.push(AttachmentType::Bytes {
+                    data: board_image.into(),
+                    filename: "position.png".to_string(),
+                }),
+                Err(err) => {
+                    warn!("Failed to render board: {}", err)
+                }
             }
+            request.reply_with_files(reply, files).await?;
+            Ok(())
+        }
+    }
+}
+
+/// The engine's top moves as a ranked table, with scores for white
+fn candidate_table(candidates: &[Candidate]) -> String {
+    let mut table = format!(
+        "{:>2}  {:<8} {:>6} {:>9}  PV\n",
+        "#", "Move", "Score", "Visits"
+    );
+    for (rank, candidate) in candidates.iter().take(TPS_CANDIDATES_SHOWN).enumerate() {
+        let visits = if candidate.visits > 0 {
+            candidate.visits.to_string()
+        } else {
+            "-".to_string()
         };
-        Ok(())
+        table.push_str(&format!(
+            "{:>2}  {:<8} {:>5.1}% {:>9}  {}\n",
+            rank + 1,
+            candidate.mv,
+            candidate.score * 100.0,
+            visits,
+            pv_text(&candidate.pv, 6)
+        ));
+    }
+    table
+}
+
+/// The backend may not support every size that the bot does
+fn check_backend_size(size: usize) -> Result<usize, String> {
+    let sizes = BACKEND.get().unwrap().supported_sizes();
+    if sizes.contains(&size) {
+        Ok(size)
     } else {
-        msg.reply(
-            ctx,
-            "Couldn't determine board size. The PTN must include a tag such as [Size \"6\"].",
-        )
-        .await?;
-        Ok(())
+        Err(format!(
+            "Size {size} is not supported by this bot's engine. The size must be between {} and {}.",
+            sizes.start(),
+            sizes.end()
+        ))
+    }
+}
+
+fn experimental_size_note(size: usize) -> String {
+    format!("Note: Tiltak's evaluation is experimental on {size}s. The analysis may be less accurate than on other sizes.")
+}
+
+// Half-komis that `Position::value_params` has evaluation parameters for, on each size
+const TUNED_HALF_KOMIS: [(usize, &[i8]); 6] = [
+    (3, &[0, 4]),
+    (4, &[0, 4]),
+    (5, &[0, 3, 4, 5]),
+    (6, &[0, 3, 4, 5]),
+    (7, &[0, 4]),
+    (8, &[0, 4]),
+];
+
+fn tuned_komis(size: usize) -> Vec<Komi> {
+    TUNED_HALF_KOMIS
+        .iter()
+        .find(|(tuned_size, _)| *tuned_size == size)
+        .map_or(&[][..], |(_, half_komis)| half_komis)
+        .iter()
+        .filter_map(|half_komi| Komi::from_half_komi(*half_komi))
+        .collect()
+}
+
+/// Komi used for the heuristic evaluation, because not all komis have a tuned evaluation function
+/// Uses the user's choice if given, otherwise the game's komi if possible, or else the closest tuned komi
+fn eval_komi(size: usize, komi: Komi, requested: Option<Komi>) -> Result<Komi, String> {
+    let tuned_komis = tuned_komis(size);
+    match requested {
+        Some(requested) if tuned_komis.contains(&requested) => Ok(requested),
+        Some(requested) => Err(format!(
+            "There is no tuned evaluation for {requested} komi on {size}s. Pick eval_komi from {}.",
+            tuned_komis
+                .iter()
+                .map(|komi| komi.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )),
+        None if tuned_komis.contains(&komi) => Ok(komi),
+        // Prefer the higher komi if two are equally close, as the bot always has
+        None => tuned_komis
+            .iter()
+            .min_by_key(|tuned| {
+                (
+                    (tuned.half_komi() - komi.half_komi()).abs(),
+                    std::cmp::Reverse(tuned.half_komi()),
+                )
+            })
+            .copied()
+            .ok_or_else(|| format!("There is no tuned evaluation for {size}s.")),
     }
 }
 
-#[command]
-async fn analyze_tps(ctx: &Context, msg: &Message) -> CommandResult {
-    if let Some((_, tps)) = msg.content.split_once(|ch: char| ch.is_whitespace()) {
-        let size = tps.chars().filter(|ch| *ch == '/').count() + 1;
-        println!(
-            "Received {} with tps {} size {} from {}",
-            msg.content, tps, size, msg.author.name
-        );
-        match size {
-            0..=3 => msg.reply(ctx, "Couldn't read tps.").await?,
-            4..=6 => msg.reply(ctx, "Not implemented yet!").await?,
-            s => msg.reply(ctx, format!("Size {s} is unsupported.")).await?,
-        };
-        Ok(())
+fn eval_komi_note(size: usize, komi: Komi, eval_komi: Komi, user_picked: bool) -> String {
+    if user_picked {
+        format!(
+            "Note: Until the endgame, positions will be evaluated as if they had {eval_komi} komi."
+        )
     } else {
-        msg.reply(ctx, "Couldn't read tps.").await?;
-        Ok(())
+        format!("Note: {komi} komi on {size}s has no tuned evaluation. Until the endgame, positions will be evaluated as if they had {eval_komi} komi. Pick a different komi with eval_komi=<komi>.")
     }
 }
 
@@ -240,217 +1070,703 @@ struct GameAnalysis {
 }
 
 async fn analyze_ptn_sized<const S: usize>(
-    ctx: &Context,
-    msg: &Message,
+    request: &Request<'_>,
     ptn: &str,
+    options: PtnOptions,
 ) -> CommandResult {
-    match tiltak::ptn::ptn_parser::parse_ptn::<Position<S>>(ptn) {
-        Ok(games) => {
-            if games.is_empty() {
-                msg.reply(ctx, "Error: parsed 0 games.").await?;
-                return Ok(());
-            }
-            let game = &games[0];
+    let all_games = match tiltak::ptn::ptn_parser::parse_ptn::<Position<S>>(ptn) {
+        Ok(games) => games,
+        Err(err) => {
+            request.reply(err.to_string()).await?;
+            return Err(err);
+        }
+    };
+    if all_games.is_empty() {
+        request.reply("Error: parsed 0 games.").await?;
+        return Ok(());
+    }
 
-            if game.moves.len() > 240 {
-                msg.reply(ctx, "Game length cannot exceed 120 moves.")
+    let games: Vec<&Game<Position<S>>> = match options.game {
+        Some(number) => match all_games.get(number.wrapping_sub(1)) {
+            Some(game) => vec![game],
+            None => {
+                request
+                    .reply(format!(
+                        "There is no game #{number}, the PTN only has {} games.",
+                        all_games.len()
+                    ))
                     .await?;
                 return Ok(());
             }
+        },
+        None if all_games.len() > MAX_GAMES_PER_REQUEST => {
+            request
+                .reply(format!(
+                    "The PTN has {} games, but at most {MAX_GAMES_PER_REQUEST} can be analyzed at once. Pick one with game=<number>:\n{}",
+                    all_games.len(),
+                    game_list(&all_games)
+                ))
+                .await?;
+            return Ok(());
+        }
+        None => all_games.iter().collect(),
+    };
 
-            let komi_string = game
-                .tags
-                .iter()
-                .find_map(|(tag, value)| {
-                    if tag == "Komi" {
-                        Some(value.clone())
-                    } else {
-                        None
-                    }
-                })
-                .unwrap_or_else(|| "0".to_string());
+    let base_settings = if options.slatebot {
+        SLATEBOT_SEARCH
+    } else {
+        guild_search_settings(request, DEFAULT_PTN_SEARCH)
+    };
+    let settings = options.search.apply_to(base_settings);
+    let max_nodes = max_nodes(request, MAX_PTN_NODES);
+    if !(1..=max_nodes).contains(&settings.nodes) {
+        request
+            .reply(format!("Node count must be between 1 and {max_nodes}"))
+            .await?;
+        return Ok(());
+    }
+    if let Some(time) = options.time {
+        if !BACKEND.get().unwrap().supports_move_time() {
+            request
+                .reply("This bot's engine can't analyze with a time budget. Pick a node count instead.")
+                .await?;
+            return Ok(());
+        }
+        if options.search.nodes.is_some() {
+            request
+                .reply("Pick either a node count or a time, not both.")
+                .await?;
+            return Ok(());
+        }
+        if !(MIN_ANALYSIS_TIME..=MAX_ANALYSIS_TIME).contains(&time) {
+            request
+                .reply(format!(
+                    "Time must be between {}s and {}s",
+                    MIN_ANALYSIS_TIME.as_secs(),
+                    MAX_ANALYSIS_TIME.as_secs()
+                ))
+                .await?;
+            return Ok(());
+        }
+    }
+
+    let mut komis = vec![];
+    for (i, game) in games.iter().enumerate() {
+        // Only name the game in messages if there are several
+        let game_prefix = if games.len() > 1 {
+            format!("Game #{}: ", i + 1)
+        } else {
+            String::new()
+        };
+
+        if game.moves.len() > 240 {
+            request
+                .reply(format!("{game_prefix}Game length cannot exceed 120 moves."))
+                .await?;
+            return Ok(());
+        }
+
+        let komi_string = game
+            .tags
+            .iter()
+            .find_map(|(tag, value)| {
+                if tag == "Komi" {
+                    Some(value.clone())
+                } else {
+                    None
+                }
+            })
+            .unwrap_or_else(|| "0".to_string());
 
-            let komi = match Komi::from_str(&komi_string) {
+        let komi = match options.komi {
+            Some(komi) => komi,
+            None => match Komi::from_str(&komi_string) {
                 Ok(komi) => komi,
                 Err(_) => {
-                    msg.reply(ctx, format!("Couldn't analyze with {komi_string} komi"))
+                    request
+                        .reply(format!(
+                            "{game_prefix}Couldn't analyze with {komi_string} komi"
+                        ))
                         .await?;
                     return Ok(());
                 }
-            };
+            },
+        };
 
-            let eval_komi = match komi.half_komi() {
-                ..=1 => Komi::from_half_komi(0).unwrap(),
-                2.. => Komi::from_half_komi(4).unwrap(),
-            };
+        let eval_komi = match eval_komi(S, komi, options.eval_komi) {
+            Ok(eval_komi) => eval_komi,
+            Err(error) => {
+                request.reply(format!("{game_prefix}{error}")).await?;
+                return Ok(());
+            }
+        };
 
-            if komi != eval_komi {
-                msg.reply(
-                    ctx,
-                    format!("Note: {komi} komi on {S}s is not fully supported. Until the endgame, the game will be evaluated as if it had {eval_komi} komi."),
-                )
+        if komi != eval_komi {
+            request
+                .reply(format!(
+                    "{game_prefix}{}",
+                    eval_komi_note(S, komi, eval_komi, options.eval_komi.is_some())
+                ))
                 .await?;
-            }
+        }
+        komis.push((komi, eval_komi));
+    }
+
+    if EXPERIMENTAL_SIZES.contains(&S) {
+        request.reply(experimental_size_note(S)).await?;
+    }
 
-            if GAMES_ANALYZED.load(Ordering::SeqCst) > MAX_GAMES_ANALYZED {
-                msg.reply(ctx, "Too many games analyzed recently. Try again later.")
+    // Every game counts against the rate limits, but only if the analysis succeeds
+    let rate_limiter = RATE_LIMITER.get().unwrap();
+    let mut reservations = vec![];
+    for _ in games.iter() {
+        match rate_limiter.try_reserve(
+            request.guild_id().map_or(0, |id| id.0),
+            request.author().id.0,
+        ) {
+            Ok(reservation) => reservations.push(reservation),
+            Err(rate_limited) if reservations.is_empty() => {
+                request.reply(rate_limited_message(rate_limited)).await?;
+                return Ok(());
+            }
+            Err(_) => {
+                request
+                    .reply(format!(
+                        "You can only analyze {} more games right now. Pick one with game=<number>:\n{}",
+                        reservations.len(),
+                        game_list(&all_games)
+                    ))
                     .await?;
                 return Ok(());
-            } else {
-                GAMES_ANALYZED.fetch_add(1, Ordering::SeqCst);
             }
+        }
+    }
 
-            let Ok(_permit) = CURRENTLY_ANALYZING.try_acquire() else {
-                msg.reply(
-                    ctx,
-                    "Cannot analyze more than two games simultaneously. Try again later.",
-                )
+    let Ok(mut ticket) = ANALYSIS_QUEUE.join() else {
+        request.reply(
+            format!("The analysis queue is full, with {MAX_QUEUE_LENGTH} games waiting. Try again later."),
+        )
+        .await?;
+        return Ok(());
+    };
+    let (permit, queue_message) = wait_in_queue(request, &mut ticket, "game").await?;
+
+    let total_plies: usize = games.iter().map(|game| game.moves.len() + 1).sum();
+    let mut status_message = match queue_message {
+        Some(mut queue_message) => {
+            request
+                .edit_reply(&mut queue_message, progress_text(0, total_plies, None))
                 .await?;
-                return Ok(());
-            };
+            queue_message
+        }
+        None => request.reply(progress_text(0, total_plies, None)).await?,
+    };
 
-            let typing = Typing::start(ctx.http.clone(), msg.channel_id.0)?;
+    let typing = request.start_typing()?;
 
-            let start_time = time::Instant::now();
+    let start_time = time::Instant::now();
 
-            let futures = (0..=game.moves.len()).map(|i| {
-                let moves = game.moves[0..i]
-                    .iter()
-                    .map(|ptn_move| ptn_move.mv.to_string())
-                    .collect();
+    let backend = BACKEND.get().unwrap().as_ref();
+    let cache = CACHE.get().unwrap();
 
-                let tps = if game.start_position != Position::start_position() {
-                    Some(game.start_position.to_fen())
-                } else {
-                    None
-                };
-                if msg.content.starts_with("!analyze_ptn_slatebot") {
-                    aws::pv_aws(S, tps, moves, 100_000, 1000, komi, eval_komi)
-                } else if msg.content.starts_with("!analyze_ptn") {
-                    aws::pv_aws(S, tps, moves, 1_000_000, 0, komi, eval_komi)
-                } else {
-                    panic!(
-                        "Failed to parse command {}",
-                        msg.content.split_whitespace().next().unwrap_or_default()
-                    )
+    // With a time budget, complex positions get more of it than simple ones
+    let move_times: Vec<Option<Vec<Duration>>> = match options.time {
+        Some(budget) => {
+            let weights: Vec<Vec<f64>> = games
+                .iter()
+                .map(|game| time_budget::position_weights(*game))
+                .collect();
+            let mut move_times =
+                time_budget::move_times(budget, &weights.concat(), backend.parallel_searches())
+                    .into_iter();
+            weights
+                .iter()
+                .map(|game_weights| Some(move_times.by_ref().take(game_weights.len()).collect()))
+                .collect()
+        }
+        None => vec![None; games.len()],
+    };
+
+    let mut pending_plies: FuturesUnordered<_> = games
+        .iter()
+        .zip(&komis)
+        .zip(move_times)
+        .enumerate()
+        .flat_map(|(game_index, ((game, (komi, eval_komi)), move_times))| {
+            analyze_plies(
+                backend, cache, game, settings, move_times, *komi, *eval_komi,
+            )
+            .into_iter()
+            .enumerate()
+            .map(move |(ply, analysis)| async move { (game_index, ply, analysis.await) })
+        })
+        .collect();
+
+    let cancel_handle = ACTIVE_ANALYSES.register(
+        request.author().id.0,
+        request.channel_id().0,
+        status_message.id.0,
+    );
+    if let Err(err) = status_message.react(request.ctx, CANCEL_EMOJI).await {
+        warn!("Failed to add cancel reaction: {}", err);
+    }
+
+    let mut outputs: Vec<Vec<Option<Output>>> = games
+        .iter()
+        .map(|game| vec![None; game.moves.len() + 1])
+        .collect();
+    let mut plies_analyzed = 0;
+    let mut cached_plies = 0;
+    let mut time_saved = Duration::ZERO;
+    let mut progress_interval = tokio::time::interval_at(
+        tokio::time::Instant::now() + PROGRESS_UPDATE_INTERVAL,
+        PROGRESS_UPDATE_INTERVAL,
+    );
+    let mut cancelled = false;
+    let mut error = None;
+    while plies_analyzed < total_plies {
+        tokio::select! {
+            Some((game_index, ply, result)) = pending_plies.next() => match result {
+                Ok(analysis) => {
+                    if analysis.cached {
+                        cached_plies += 1;
+                        time_saved += analysis.output.time_taken;
+                    }
+                    outputs[game_index][ply] = Some(analysis.output);
+                    plies_analyzed += 1;
                 }
-            });
-            let results = futures::future::join_all(futures).await;
-
-            typing.stop().unwrap();
-
-            // Some trickery to transform Vec<Result<_>> into Result<Vec<_>>
-            let result_results: Result<Vec<_>, _> = results.into_iter().collect();
-            match result_results {
-                Err(error) => {
-                    warn!("AWS error: {}", error);
-                    msg.reply(ctx, "AWS error.").await?;
-                    Err("AWS error".into())
+                Err(err) => {
+                    error = Some(err);
+                    break;
                 }
-                Ok(outputs) => {
-                    let slowest_output = outputs
-                        .iter()
-                        .cloned()
-                        .max_by_key(|output| output.time_taken)
-                        .unwrap_or_default();
-                    let highest_memory_usage = outputs
-                        .iter()
-                        .cloned()
-                        .max_by_key(|output| output.mem_usage)
-                        .unwrap_or_default();
-                    let (file_contents, white_name, black_name) = process_aws_output(game, outputs);
-                    let annotated_game = std::str::from_utf8(file_contents.as_slice()).unwrap();
-                    println!("{}", annotated_game);
-
-                    println!(
-                        "{:.1}s taken total, {:.1}s taken for slowest pv {:?}, {:.1}MiB for largest tree",
-                        start_time.elapsed().as_secs_f32(),
-                        slowest_output.time_taken.as_secs_f32(),
-                        slowest_output.pv,
-                        highest_memory_usage.mem_usage as f32 / (1024.0 * 1024.0),
-                    );
-
-                    let graph_start_time = time::Instant::now();
-                    let graph = eval_graph::generate_graph(&file_contents);
-                    println!(
-                        "Rendered graph in {:.2}s",
-                        graph_start_time.elapsed().as_secs_f32()
-                    );
-
-                    let channel = msg.channel(&ctx).await.unwrap();
-
-                    let url_start_time = time::Instant::now();
-                    let short_ptn_ninja_url = create_short_ptn_ninja_url(annotated_game).await;
-                    println!(
-                        "Got shortened URL in {:.2}s",
-                        url_start_time.elapsed().as_secs_f32()
-                    );
-
-                    let ptn_ninja_message = match short_ptn_ninja_url {
-                        // wrap URL in `<...>` to prevent discord preview
-                        Ok(url) => format!("[View game in ptn.ninja](<{}>).", url),
-                        Err(err) => {
-                            warn!("Error shortening ptn.ninja URL: {}", err);
-                            "Best viewed in ptn.ninja!".to_string()
-                        }
-                    };
-
-                    channel
-                        .id()
-                        .send_message(&ctx.http, |m| {
-                            m.content(format!(
-                                "Finished analyzing {} vs {} in {:.1}s. {}",
-                                white_name,
-                                black_name,
-                                start_time.elapsed().as_secs_f32(),
-                                ptn_ninja_message,
-                            ));
-                            m.add_file(AttachmentType::Bytes {
-                                data: file_contents.into(),
-                                filename: format!("{white_name}_vs_{black_name}.txt"),
-                            });
-                            match graph {
-                                Ok(graph) => {
-                                    m.add_file(AttachmentType::Bytes {
-                                        data: graph.into(),
-                                        filename: format!("{white_name}_vs_{black_name}.png"),
-                                    });
-                                }
-                                Err(err) => {
-                                    warn!("Failed to render eval graph: {}", err)
-                                }
-                            }
-                            m
-                        })
-                        .await?;
-                    Ok(())
+            },
+            _ = progress_interval.tick() => {
+                let text = progress_text(plies_analyzed, total_plies, Some(start_time.elapsed()));
+                if let Err(err) = request.edit_reply(&mut status_message, text).await {
+                    warn!("Failed to update progress message: {}", err);
                 }
             }
+            _ = cancel_handle.cancelled() => {
+                cancelled = true;
+                break;
+            }
         }
-        Err(err) => {
-            msg.reply(ctx, err.to_string()).await?;
-            Err(err)
+    }
+    // Dropping the remaining searches stops them, except for remote searches that only stop being waited for
+    drop(pending_plies);
+    drop(cancel_handle);
+    drop(permit);
+
+    if let Some(typing) = typing {
+        typing.stop();
+    }
+
+    if let Some(error) = error {
+        warn!("Analysis error: {}", error);
+        if let Err(err) = request
+            .edit_reply(&mut status_message, "Analysis failed.")
+            .await
+        {
+            warn!("Failed to update progress message: {}", err);
+        }
+        request.reply("Analysis error.").await?;
+        return Err("Analysis error".into());
+    }
+
+    let status_text = if cancelled {
+        format!("Analysis cancelled after {plies_analyzed}/{total_plies} plies.")
+    } else {
+        format!(
+            "Analyzed {total_plies} plies in {:.1}s.",
+            start_time.elapsed().as_secs_f32()
+        )
+    };
+    if let Err(err) = request.edit_reply(&mut status_message, status_text).await {
+        warn!("Failed to update progress message: {}", err);
+    }
+
+    // Plies may finish in any order, but only an unbroken sequence from the start can be annotated
+    // At least one move of a game must be analyzed to show anything
+    let analyzed_games: Vec<(&Game<Position<S>>, Vec<Output>)> = games
+        .iter()
+        .zip(outputs)
+        .map(|(game, outputs)| {
+            (
+                *game,
+                outputs
+                    .into_iter()
+                    .map_while(|output| output)
+                    .collect::<Vec<_>>(),
+            )
+        })
+        .filter(|(_, outputs)| outputs.len() >= 2)
+        .collect();
+    if analyzed_games.is_empty() {
+        return Ok(());
+    }
+
+    let mut note = String::new();
+    if cached_plies > 0 {
+        note.push_str(&format!(
+            "\n{cached_plies} plies were already analyzed, saving {:.1}s of engine time.",
+            time_saved.as_secs_f32()
+        ));
+    }
+    if cancelled {
+        match analyzed_games.as_slice() {
+            [(_, outputs)] if games.len() == 1 => note.push_str(&format!(
+                "\nAnalysis was cancelled, only the first {} plies were analyzed.",
+                outputs.len() - 1
+            )),
+            _ => {
+                note.push_str("\nAnalysis was cancelled, so some games are incomplete or missing.")
+            }
+        }
+    }
+
+    for reservation in reservations {
+        reservation.commit();
+    }
+    send_analysis_result(request, analyzed_games, start_time, &note).await
+}
+
+/// Numbered list of the games in a PTN file, to pick one from
+fn game_list<const S: usize>(games: &[Game<Position<S>>]) -> String {
+    games
+        .iter()
+        .enumerate()
+        .map(|(i, game)| {
+            let (white_name, black_name) = player_names(game);
+            format!("{}. {white_name} vs {black_name}", i + 1)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+async fn send_analysis_result<const S: usize>(
+    request: &Request<'_>,
+    analyzed_games: Vec<(&Game<Position<S>>, Vec<Output>)>,
+    start_time: time::Instant,
+    note: &str,
+) -> CommandResult {
+    let single_game = analyzed_games.len() == 1;
+    let mut ptn_file = Vec::new();
+    let mut files = vec![];
+    let mut summary = String::new();
+    let mut game_names = vec![];
+    let mut embeds = vec![];
+
+    for (i, (game, outputs)) in analyzed_games.into_iter().enumerate() {
+        let slowest_output = outputs
+            .iter()
+            .cloned()
+            .max_by_key(|output| output.time_taken)
+            .unwrap_or_default();
+        let highest_memory_usage = outputs
+            .iter()
+            .cloned()
+            .max_by_key(|output| output.mem_usage)
+            .unwrap_or_default();
+        let move_scores: Vec<f32> = outputs.iter().map(|output| output.score).collect();
+        let move_qualities = classify_moves(game, &outputs);
+        let (file_contents, analysis_summary) = process_aws_output(game, outputs, &move_qualities);
+        let white_name = analysis_summary.white_name.clone();
+        let black_name = analysis_summary.black_name.clone();
+        game_names.push((white_name.clone(), black_name.clone()));
+        embeds.push(analysis_summary.embed());
+        println!("{}", String::from_utf8_lossy(&file_contents));
+
+        println!(
+            "{:.1}s taken total, {:.1}s taken for slowest pv {:?}, {:.1}MiB for largest tree",
+            start_time.elapsed().as_secs_f32(),
+            slowest_output.time_taken.as_secs_f32(),
+            slowest_output.pv,
+            highest_memory_usage.mem_usage as f32 / (1024.0 * 1024.0),
+        );
+
+        if !ptn_file.is_empty() {
+            ptn_file.push(b'\n');
+        }
+        ptn_file.extend_from_slice(&file_contents);
+
+        let graph_start_time = time::Instant::now();
+        let blunder_plies: Vec<usize> = move_qualities
+            .iter()
+            .enumerate()
+            .filter(|(_, quality)| **quality == MoveQuality::Blunder)
+            .map(|(ply, _)| ply)
+            .collect();
+        let graph = eval_graph::generate_graph(
+            &move_scores[1..],
+            &format!("{white_name} vs {black_name}"),
+            &blunder_plies,
+            game.start_position.side_to_move() == Color::White,
+        );
+        println!(
+            "Rendered graph in {:.2}s",
+            graph_start_time.elapsed().as_secs_f32()
+        );
+        // Leave room for the PTN file within Discord's attachment limit
+        if files.len() < MAX_ATTACHMENTS - 1 {
+            match graph {
+                Ok(graph) => files.push(AttachmentType::Bytes {
+                    data: graph.into(),
+                    filename: if single_game {
+                        format!("{white_name}_vs_{black_name}.png")
+                    } else {
+                        format!("game_{}_{white_name}_vs_{black_name}.png", i + 1)
+                    },
+                }),
+                Err(err) => {
+                    warn!("Failed to render eval graph: {}", err)
+                }
+            }
+        }
+
+        let blunders = biggest_blunders(game, &move_scores, &move_qualities);
+        let blunder_names = blunders
+            .iter()
+            .map(|(move_name, _)| move_name.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+        if single_game {
+            if !blunders.is_empty() {
+                summary = format!("\nBiggest blunders: {blunder_names}");
+            }
+            for (i, (_, board_image)) in blunders.into_iter().enumerate() {
+                match board_image {
+                    Ok(board_image) => files.push(AttachmentType::Bytes {
+                        data: board_image.into(),
+                        filename: format!("blunder_{}.png", i + 1),
+                    }),
+                    Err(err) => {
+                        warn!("Failed to render board: {}", err)
+                    }
+                }
+            }
+        } else {
+            summary.push_str(&format!(
+                "\n{}. {white_name} vs {black_name}, {}",
+                i + 1,
+                game.game_result_str.unwrap_or("unfinished")
+            ));
+            if !blunders.is_empty() {
+                summary.push_str(&format!(". Biggest blunders: {blunder_names}"));
+            }
+        }
+    }
+
+    let (content, ptn_filename) = match game_names.as_slice() {
+        [(white_name, black_name)] => {
+            let url_start_time = time::Instant::now();
+            let short_ptn_ninja_url =
+                create_short_ptn_ninja_url(&String::from_utf8_lossy(&ptn_file)).await;
+            println!(
+                "Got shortened URL in {:.2}s",
+                url_start_time.elapsed().as_secs_f32()
+            );
+
+            let ptn_ninja_message = match short_ptn_ninja_url {
+                // wrap URL in `<...>` to prevent discord preview
+                Ok(url) => format!("[View game in ptn.ninja](<{}>).", url),
+                Err(err) => {
+                    warn!("Error shortening ptn.ninja URL: {}", err);
+                    "Best viewed in ptn.ninja!".to_string()
+                }
+            };
+            (
+                format!(
+                    "Finished analyzing {} vs {} in {:.1}s. {}{}{}",
+                    white_name,
+                    black_name,
+                    start_time.elapsed().as_secs_f32(),
+                    ptn_ninja_message,
+                    summary,
+                    note,
+                ),
+                format!("{white_name}_vs_{black_name}.txt"),
+            )
+        }
+        _ => (
+            format!(
+                "Finished analyzing {} games in {:.1}s.{}{}",
+                game_names.len(),
+                start_time.elapsed().as_secs_f32(),
+                summary,
+                note,
+            ),
+            "games.txt".to_string(),
+        ),
+    };
+    files.insert(
+        0,
+        AttachmentType::Bytes {
+            data: ptn_file.into(),
+            filename: ptn_filename,
+        },
+    );
+    request.reply_with_embeds(content, embeds, files).await?;
+    Ok(())
+}
+
+struct PlyAnalysis {
+    output: Output,
+    cached: bool,
+}
+
+/// Searches of the start position and the position after every ply of the game
+/// The searches run concurrently once polled, and are aborted if dropped
+/// Positions that are in the cache are not searched again
+/// With `move_times`, each position is searched for that long instead of a fixed node count
+fn analyze_plies<'a, const S: usize>(
+    backend: &'a dyn AnalysisBackend,
+    cache: &'a AnalysisCache,
+    game: &Game<Position<S>>,
+    settings: SearchSettings,
+    move_times: Option<Vec<Duration>>,
+    komi: Komi,
+    eval_komi: Komi,
+) -> Vec<BoxFuture<'a, io::Result<PlyAnalysis>>> {
+    let tps = if game.start_position != Position::start_position() {
+        Some(game.start_position.to_fen())
+    } else {
+        None
+    };
+
+    (0..=game.moves.len())
+        .map(|i| {
+            let moves = game.moves[0..i]
+                .iter()
+                .map(|ptn_move| ptn_move.mv.to_string())
+                .collect();
+            let mut event = Event::new(S, tps.clone(), moves, settings, komi, eval_komi);
+            if let Some(move_times) = &move_times {
+                event.time_control = TimeControl::MoveTime(move_times[i]);
+            }
+            async move {
+                if let Some(output) = cache.get(&event).await {
+                    return Ok(PlyAnalysis {
+                        output,
+                        cached: true,
+                    });
+                }
+                let output = backend.analyze(event.clone()).await?;
+                cache.insert(&event, &output).await;
+                Ok(PlyAnalysis {
+                    output,
+                    cached: false,
+                })
+            }
+            .boxed()
+        })
+        .collect()
+}
+
+fn progress_text(plies_analyzed: usize, total_plies: usize, elapsed: Option<Duration>) -> String {
+    let mut text = format!("Analyzing... {plies_analyzed}/{total_plies} plies done");
+    if let Some(elapsed) = elapsed.filter(|_| plies_analyzed > 0) {
+        let remaining =
+            elapsed.as_secs_f32() * (total_plies - plies_analyzed) as f32 / plies_analyzed as f32;
+        text.push_str(&format!(", ~{remaining:.0}s remaining"));
+    }
+    text
+}
+
+/// Wait until the analysis can start, telling the user their place in the queue while it waits
+/// Returns the message that showed the place in the queue, if the analysis had to wait
+async fn wait_in_queue<'a>(
+    request: &Request<'_>,
+    ticket: &mut QueueTicket<'a>,
+    item_name: &str,
+) -> serenity::Result<(QueuePermit<'a>, Option<Message>)> {
+    let mut queue_message: Option<Message> = None;
+    let mut queue_position = None;
+    loop {
+        match ticket.next_status(queue_position).await {
+            Ok(permit) => return Ok((permit, queue_message)),
+            Err(position) => {
+                queue_position = Some(position);
+                let queue_text = format!(
+                    "Other games are being analyzed. Your {item_name} is #{position} in the queue."
+                );
+                match queue_message.as_mut() {
+                    Some(queue_message) => request.edit_reply(queue_message, queue_text).await?,
+                    None => queue_message = Some(request.reply(queue_text).await?),
+                }
+            }
         }
     }
 }
 
+fn rate_limited_message(rate_limited: RateLimited) -> String {
+    let reason = match rate_limited.scope {
+        LimitScope::User => "You have analyzed too many games recently",
+        LimitScope::Guild => "Too many games analyzed in this server recently",
+        LimitScope::Global => "Too many games analyzed recently",
+    };
+    // Discord shows this as a relative timestamp, like "in 5 minutes"
+    let retry_timestamp = (SystemTime::now() + rate_limited.retry_after)
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        + 1;
+    format!("{reason}. Try again <t:{retry_timestamp}:R>.")
+}
+
 fn process_aws_output<const S: usize>(
     game: &Game<Position<S>>,
     outputs: Vec<Output>,
-) -> (Vec<u8>, String, String) {
-    let (move_scores, pv_strings): (Vec<f32>, Vec<Vec<String>>) = outputs
-        .into_iter()
-        .map(|Output { score, pv, .. }| (score, pv))
-        .unzip();
-    let move_annotations = annotate_move_scores(&move_scores);
+    move_qualities: &[MoveQuality],
+) -> (Vec<u8>, GameSummary) {
+    let move_scores: Vec<f32> = outputs.iter().map(|output| output.score).collect();
+    let move_annotations = move_qualities.iter().map(|quality| quality.annotation());
+
+    // Player1 and Player2 are white and black in PTN
+    let mut tags = game.tags.clone();
+    let white_moves_first = game.start_position.side_to_move() == Color::White;
+    let players = classification::player_summaries(&move_scores, move_qualities, white_moves_first);
+    for (tag, summary) in ["Player1Accuracy", "Player2Accuracy"].iter().zip(players) {
+        tags.retain(|(name, _)| name != tag);
+        tags.push((tag.to_string(), format!("{:.1}", summary.accuracy)));
+    }
 
-    let comments = move_scores
+    let comments = game
+        .moves
         .iter()
-        .skip(1)
-        .zip(pv_strings)
-        .map(|(score, pv)| {
-            let pv_moves = pv.iter().take(3).map(String::as_str).collect::<Vec<&str>>();
-            format!("{:.1}%, pv {}", score * 100.0, pv_moves.join(" "))
+        .zip(outputs.windows(2))
+        .zip(move_qualities)
+        .map(|((ptn_move, outputs), quality)| {
+            let (before, after) = (&outputs[0], &outputs[1]);
+            let mut comment = format!("{:.1}%, pv {}", after.score * 100.0, pv_text(&after.pv, 3));
+            let played_move = ptn_move.mv.to_string();
+            // Point out what the engine preferred, so the reader doesn't have to look at the previous ply
+            // The line goes in the comment, because `PtnMove` has no field for a variation
+            let is_mistake = matches!(
+                quality,
+                MoveQuality::Inaccuracy | MoveQuality::Mistake | MoveQuality::Blunder
+            );
+            if let Some(best_move) = before.pv.first() {
+                if is_mistake && *best_move != played_move {
+                    comment.push_str(&format!(
+                        ". Better was {} ({:.1}%), line {}",
+                        best_move,
+                        before.score * 100.0,
+                        pv_text(&before.pv, 4)
+                    ));
+                }
+            }
+            // At critical moments, also list the other moves the engine considered
+            let alternatives: Vec<String> = before
+                .candidates
+                .iter()
+                .filter(|candidate| candidate.mv != played_move)
+                .take(MAX_ALTERNATIVES_SHOWN)
+                .map(|candidate| format!("{} {:.1}%", candidate.mv, candidate.score * 100.0))
+                .collect();
+            if *quality != MoveQuality::Normal && !alternatives.is_empty() {
+                comment.push_str(&format!(". Alternatives: {}", alternatives.join(", ")));
+            }
+            comment
         });
 
     let annotated_game = Game {
@@ -471,68 +1787,124 @@ fn process_aws_output<const S: usize>(
             })
             .collect(),
         game_result_str: game.game_result_str,
-        tags: game.tags.clone(),
+        tags,
     };
 
-    let white_name = annotated_game
-        .tags
-        .iter()
-        .find_map(|(tag, value)| {
-            if tag == "Player1" {
-                Some(value.clone())
-            } else {
-                None
-            }
-        })
-        .unwrap_or_else(|| "?".to_string());
-
-    let black_name = annotated_game
-        .tags
+    let (white_name, black_name) = player_names(&annotated_game);
+    let played_moves: Vec<String> = game
+        .moves
         .iter()
-        .find_map(|(tag, value)| {
-            if tag == "Player2" {
-                Some(value.clone())
-            } else {
-                None
-            }
-        })
-        .unwrap_or_else(|| "?".to_string());
+        .map(|ptn_move| ptn_move.mv.to_string())
+        .collect();
+    let decided = match game_summary::decisive_ply(&move_scores) {
+        Decided::FromStart => Decided::FromStart,
+        Decided::After(ply) => Decided::After(game_summary::move_name(
+            ply,
+            &played_moves[ply],
+            white_moves_first,
+        )),
+        Decided::Never => Decided::Never,
+    };
+    let analysis_summary = GameSummary {
+        white_name,
+        black_name,
+        result: game
+            .game_result_str
+            .map(str::to_string)
+            .or_else(|| tag_value(game, "Result")),
+        time_control: tag_value(game, "Clock"),
+        players,
+        key_moments: game_summary::key_moments(&played_moves, &outputs, white_moves_first),
+        decided,
+    };
 
     let mut buffer = Vec::new();
     annotated_game.game_to_ptn(&mut buffer).unwrap();
-    (buffer, white_name, black_name)
+    (buffer, analysis_summary)
 }
 
-fn annotate_move_scores(move_scores: &[f32]) -> Vec<&'static str> {
-    move_scores
-        .windows(2)
-        .enumerate()
-        .map(|(i, scores)| {
-            let last_score = scores[0];
-            let score = scores[1];
-
-            let score_loss = if i % 2 == 0 {
-                // The current move was made by white
-                score - last_score
-            } else {
-                last_score - score
-            };
+fn player_names<const S: usize>(game: &Game<Position<S>>) -> (String, String) {
+    let player_name = |tag: &str| tag_value(game, tag).unwrap_or_else(|| "?".to_string());
+    (player_name("Player1"), player_name("Player2"))
+}
+
+fn tag_value<const S: usize>(game: &Game<Position<S>>, name: &str) -> Option<String> {
+    game.tags.iter().find_map(|(tag, value)| {
+        if tag == name {
+            Some(value.clone())
+        } else {
+            None
+        }
+    })
+}
+
+/// The blunders with the biggest score loss, with a diagram of the position after each of them
+fn biggest_blunders<const S: usize>(
+    game: &Game<Position<S>>,
+    move_scores: &[f32],
+    move_qualities: &[MoveQuality],
+) -> Vec<(String, io::Result<Vec<u8>>)> {
+    let white_moves_first = game.start_position.side_to_move() == Color::White;
+    let mut blunders: Vec<(usize, f32)> =
+        classification::eval_changes(move_scores, white_moves_first)
+            .into_iter()
+            .zip(move_qualities)
+            .enumerate()
+            .filter(|(_, (_, quality))| **quality == MoveQuality::Blunder)
+            .map(|(ply, (eval_change, _))| (ply, eval_change))
+            .collect();
+    blunders.sort_by(|(_, change1), (_, change2)| change1.total_cmp(change2));
 
-            if score_loss > 0.06 {
-                "!!"
-            } else if score_loss > 0.03 {
-                "!"
-            } else if score_loss > -0.1 {
-                ""
-            } else if score_loss > -0.25 {
-                "?"
-            } else {
-                "??"
+    blunders
+        .into_iter()
+        .take(MAX_BLUNDER_DIAGRAMS)
+        .map(|(ply, _)| {
+            let mut position = game.start_position.clone();
+            for ptn_move in game.moves[0..=ply].iter() {
+                position.do_move(ptn_move.mv);
             }
+            let move_string = game.moves[ply].mv.to_string();
+            let move_name = game_summary::move_name(ply, &move_string, white_moves_first);
+            let board_image = board_image::render_board(&position, Some(&move_string));
+            (move_name, board_image)
         })
         .collect()
 }
 
+/// The first moves of a principal variation
+fn pv_text(pv: &[String], max_moves: usize) -> String {
+    pv.iter()
+        .take(max_moves)
+        .map(String::as_str)
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+/// Classify each move of the game, using the search of the position before the move
+fn classify_moves<const S: usize>(
+    game: &Game<Position<S>>,
+    outputs: &[Output],
+) -> Vec<MoveQuality> {
+    let move_scores: Vec<f32> = outputs.iter().map(|output| output.score).collect();
+    let white_moves_first = game.start_position.side_to_move() == Color::White;
+    let best_move_margins: Vec<Option<f32>> = game
+        .moves
+        .iter()
+        .zip(outputs)
+        .enumerate()
+        .map(|(ply, (ptn_move, output))| {
+            let white_to_move = classification::is_white_move(ply, white_moves_first);
+            classification::best_move_margin(output, &ptn_move.mv.to_string(), white_to_move)
+        })
+        .collect();
+    classification::classify_moves(
+        &move_scores,
+        &best_move_margins,
+        MOVE_THRESHOLDS.get().unwrap(),
+        white_moves_first,
+    )
+}
+
 #[derive(Serialize)]
 struct UrlPtnNinjaRequest {
     ptn: String,
@@ -555,3 +1927,96 @@ async fn create_short_ptn_ninja_url(ptn: &str) -> Result<String, reqwest::Error>
         Err(err) => Err(err),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::FakeBackend;
+
+    fn komi(half_komi: i8) -> Komi {
+        Komi::from_half_komi(half_komi).unwrap()
+    }
+
+    #[test]
+    fn tuned_komi_is_kept() {
+        assert_eq!(eval_komi(6, komi(0), None), Ok(komi(0)));
+        assert_eq!(eval_komi(6, komi(3), None), Ok(komi(3)));
+        assert_eq!(eval_komi(6, komi(5), None), Ok(komi(5)));
+        assert_eq!(eval_komi(4, komi(4), None), Ok(komi(4)));
+    }
+
+    #[test]
+    fn untuned_komi_falls_back_to_the_closest() {
+        assert_eq!(eval_komi(4, komi(5), None), Ok(komi(4)));
+        assert_eq!(eval_komi(4, komi(1), None), Ok(komi(0)));
+        // Equally close to 0 and 2 komi
+        assert_eq!(eval_komi(4, komi(2), None), Ok(komi(4)));
+        assert_eq!(eval_komi(6, komi(1), None), Ok(komi(0)));
+        assert_eq!(eval_komi(6, komi(-2), None), Ok(komi(0)));
+    }
+
+    #[test]
+    fn requested_eval_komi_must_be_tuned() {
+        assert_eq!(eval_komi(5, komi(5), Some(komi(0))), Ok(komi(0)));
+        assert_eq!(eval_komi(6, komi(0), Some(komi(5))), Ok(komi(5)));
+        assert!(eval_komi(4, komi(0), Some(komi(5))).is_err());
+    }
+
+    #[tokio::test]
+    async fn game_is_annotated_from_backend_outputs() {
+        let ptn = "[Size \"6\"]\n[Player1 \"Alice\"]\n[Player2 \"Bob\"]\n\n1. a1 f6 2. c3 d4";
+        let game = tiltak::ptn::ptn_parser::parse_ptn::<Position<6>>(ptn)
+            .unwrap()
+            .remove(0);
+        // Scores for the player who made the last move. White is clearly winning after black's d4
+        let backend = FakeBackend::new(
+            [0.5, 0.5, 0.5, 0.5, 0.1]
+                .iter()
+                .map(|&score| Output {
+                    score,
+                    ..Output::default()
+                })
+                .collect(),
+        );
+        let directory = std::env::temp_dir().join(format!("pipeline_test_{}", std::process::id()));
+        let cache = AnalysisCache::new(directory.clone(), "fake".to_string(), 100).unwrap();
+        let settings = SearchSettings {
+            nodes: 1000,
+            rollout_depth: 0,
+            rollout_temperature: 0.25,
+        };
+
+        // Searched one at a time, so that the fake backend's outputs go to the right plies
+        let mut outputs = vec![];
+        for ply_analysis in analyze_plies(&backend, &cache, &game, settings, None, komi(0), komi(0))
+        {
+            outputs.push(ply_analysis.await.unwrap().output);
+        }
+        std::fs::remove_dir_all(directory).unwrap();
+        let move_scores: Vec<f32> = outputs.iter().map(|output| output.score).collect();
+        assert_eq!(move_scores, [0.5, 0.5, 0.5, 0.5, 0.9]);
+
+        let _ = MOVE_THRESHOLDS.set(Thresholds::default());
+        let move_qualities = classify_moves(&game, &outputs);
+        assert_eq!(
+            move_qualities,
+            [
+                MoveQuality::Normal,
+                MoveQuality::Normal,
+                MoveQuality::Normal,
+                MoveQuality::Blunder
+            ]
+        );
+
+        let (ptn_file, summary) = process_aws_output(&game, outputs, &move_qualities);
+        assert!(String::from_utf8(ptn_file).unwrap().contains("d4??"));
+        assert_eq!(
+            (summary.white_name.as_str(), summary.black_name.as_str()),
+            ("Alice", "Bob")
+        );
+        assert_eq!(summary.players[0].blunders, 0);
+        assert_eq!(summary.players[1].blunders, 1);
+        assert_eq!(summary.key_moments.len(), 1);
+        assert_eq!(summary.key_moments[0].move_name, "2... d4");
+    }
+}
@@ -0,0 +1,120 @@
+// Reading the board size from PTN, before it can be parsed with the right size
+
+// Sizes the analysis backends support
+pub const MIN_SIZE: usize = 3;
+pub const MAX_SIZE: usize = 8;
+
+/// The tags of the first game in the PTN, such as `("Size", "6")` for `[Size "6"]`
+pub fn tags(ptn: &str) -> Vec<(&str, &str)> {
+    ptn.lines()
+        .map(str::trim)
+        .skip_while(|line| line.is_empty())
+        // The tag section ends at the first move
+        .take_while(|line| line.is_empty() || line.starts_with('['))
+        .flat_map(line_tags)
+        .collect()
+}
+
+/// Several tags may be on the same line
+fn line_tags(mut line: &str) -> Vec<(&str, &str)> {
+    let mut tags = vec![];
+    while let Some((tag, rest)) = parse_tag(line) {
+        tags.push(tag);
+        line = rest;
+    }
+    tags
+}
+
+/// Parse the first tag on the line, and return it with the rest of the line
+/// The value ends at its closing quote, so it may contain `]`
+fn parse_tag(line: &str) -> Option<((&str, &str), &str)> {
+    let (name, rest) = line
+        .trim_start()
+        .strip_prefix('[')?
+        .split_once(char::is_whitespace)?;
+    let (value, rest) = rest.trim_start().strip_prefix('"')?.split_once('"')?;
+    let rest = rest.trim_start().strip_prefix(']')?;
+    Some(((name, value), rest))
+}
+
+/// Board size from the Size tag, or from the TPS tag if there is none
+/// Returns an error message for the user if the size is missing or invalid
+pub fn board_size(ptn: &str) -> Result<usize, String> {
+    let tags = tags(ptn);
+    let tag_value = |name: &str| {
+        tags.iter()
+            .find(|(tag, _)| tag.eq_ignore_ascii_case(name))
+            .map(|(_, value)| *value)
+    };
+
+    let size = if let Some(size) = tag_value("Size") {
+        size.trim().parse().map_err(|_| {
+            format!("Invalid Size tag \"{size}\". The PTN must include a tag such as [Size \"6\"].")
+        })?
+    } else if let Some(tps) = tag_value("TPS") {
+        tps_size(tps).ok_or_else(|| format!("Couldn't determine board size from TPS \"{tps}\"."))?
+    } else {
+        return Err(
+            "Couldn't determine board size. The PTN must include a tag such as [Size \"6\"]."
+                .to_string(),
+        );
+    };
+    check_size(size)?;
+    Ok(size)
+}
+
+/// Board size of a TPS string, which is the number of rows
+pub fn tps_size(tps: &str) -> Option<usize> {
+    let board = tps.split_whitespace().next()?;
+    Some(board.split('/').count())
+}
+
+pub fn check_size(size: usize) -> Result<(), String> {
+    if (MIN_SIZE..=MAX_SIZE).contains(&size) {
+        Ok(())
+    } else {
+        Err(format!(
+            "Size {size} is not supported. The size must be between {MIN_SIZE} and {MAX_SIZE}."
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tags_are_parsed() {
+        assert_eq!(
+            parse_tag("[Size \"6\"] [Komi \"2\"]"),
+            Some((("Size", "6"), " [Komi \"2\"]"))
+        );
+        assert_eq!(
+            parse_tag("[Event \"Cup [round 2]\"]"),
+            Some((("Event", "Cup [round 2]"), ""))
+        );
+        assert_eq!(parse_tag("[Size 6]"), None);
+        assert_eq!(parse_tag("1. a1 f6"), None);
+    }
+
+    #[test]
+    fn tag_section() {
+        let ptn = "\n[Player1 \"a]b\"][Player2 \"c\"]\n[Size \"5\"]\n\n1. a1 e5\n[Size \"6\"]";
+        assert_eq!(
+            tags(ptn),
+            [("Player1", "a]b"), ("Player2", "c"), ("Size", "5")]
+        );
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(tps_size("x6/x6/x6/x6/x6/x6 1 1"), Some(6));
+        assert_eq!(tps_size(""), None);
+
+        assert_eq!(board_size("[size \"7\"]\n\n1. a1 g7"), Ok(7));
+        assert_eq!(board_size("[TPS \"x4/x4/x4/x4 1 1\"]"), Ok(4));
+        assert!(board_size("[Size \"six\"]").is_err());
+        assert!(board_size("[Size \"9\"]").is_err());
+        assert!(board_size("1. a1 f6").is_err());
+    }
+}
@@ -0,0 +1,157 @@
+use std::collections::VecDeque;
+use std::pin::pin;
+use std::sync::Mutex;
+use tokio::sync::Notify;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueFull;
+
+#[derive(Debug)]
+struct QueueState {
+    running: usize,
+    // Ids of the jobs waiting to start, in the order they were queued
+    waiting: VecDeque<u64>,
+    next_id: u64,
+}
+
+/// First-in-first-out queue of analysis jobs, with a limit on how many run at the same time
+pub struct AnalysisQueue {
+    state: Mutex<QueueState>,
+    // Notified whenever a job starts, finishes or leaves the queue
+    changed: Notify,
+    max_running: usize,
+    max_waiting: usize,
+}
+
+impl AnalysisQueue {
+    pub const fn new(max_running: usize, max_waiting: usize) -> Self {
+        AnalysisQueue {
+            state: Mutex::new(QueueState {
+                running: 0,
+                waiting: VecDeque::new(),
+                next_id: 0,
+            }),
+            changed: Notify::const_new(),
+            max_running,
+            max_waiting,
+        }
+    }
+
+    /// Get in line for running a job, unless the queue is already full
+    pub fn join(&self) -> Result<QueueTicket<'_>, QueueFull> {
+        let mut state = self.state.lock().unwrap();
+        if state.waiting.len() >= self.max_waiting {
+            return Err(QueueFull);
+        }
+        let id = state.next_id;
+        state.next_id += 1;
+        state.waiting.push_back(id);
+        Ok(QueueTicket { queue: self, id })
+    }
+}
+
+/// A job's place in the queue. The job leaves the queue if the ticket is dropped
+pub struct QueueTicket<'a> {
+    queue: &'a AnalysisQueue,
+    id: u64,
+}
+
+impl<'a> QueueTicket<'a> {
+    /// Start the job if it's first in line and there is room, otherwise return its 1-indexed position
+    fn try_start(&mut self) -> Result<QueuePermit<'a>, usize> {
+        let mut state = self.queue.state.lock().unwrap();
+        let position = state
+            .waiting
+            .iter()
+            .position(|id| *id == self.id)
+            .expect("Queued job is missing from the queue");
+        if position == 0 && state.running < self.queue.max_running {
+            state.waiting.pop_front();
+            state.running += 1;
+            self.queue.changed.notify_waiters();
+            Ok(QueuePermit { queue: self.queue })
+        } else {
+            Err(position + 1)
+        }
+    }
+
+    /// Wait until the job can start, or until its position in the queue is different from `last_position`
+    pub async fn next_status(
+        &mut self,
+        last_position: Option<usize>,
+    ) -> Result<QueuePermit<'a>, usize> {
+        loop {
+            // Register for notifications before checking, so that no changes are missed
+            let mut changed = pin!(self.queue.changed.notified());
+            changed.as_mut().enable();
+
+            match self.try_start() {
+                Ok(permit) => return Ok(permit),
+                Err(position) if Some(position) != last_position => return Err(position),
+                Err(_) => changed.await,
+            }
+        }
+    }
+}
+
+impl Drop for QueueTicket<'_> {
+    fn drop(&mut self) {
+        let mut state = self.queue.state.lock().unwrap();
+        let len_before = state.waiting.len();
+        state.waiting.retain(|id| *id != self.id);
+        if state.waiting.len() != len_before {
+            self.queue.changed.notify_waiters();
+        }
+    }
+}
+
+/// Held while the job is running
+pub struct QueuePermit<'a> {
+    queue: &'a AnalysisQueue,
+}
+
+impl Drop for QueuePermit<'_> {
+    fn drop(&mut self) {
+        self.queue.state.lock().unwrap().running -= 1;
+        self.queue.changed.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn full_queue_rejects_jobs() {
+        let queue = AnalysisQueue::new(1, 2);
+        let _first = queue.join().unwrap();
+        let _second = queue.join().unwrap();
+        assert_eq!(queue.join().err(), Some(QueueFull));
+    }
+
+    #[tokio::test]
+    async fn jobs_start_in_order() {
+        let queue = AnalysisQueue::new(1, 10);
+        let mut first = queue.join().unwrap();
+        let mut second = queue.join().unwrap();
+
+        let permit = first.next_status(None).await.ok().unwrap();
+        // The first job left the queue when it started
+        assert_eq!(second.next_status(None).await.err(), Some(1));
+        drop(permit);
+        assert!(second.next_status(Some(1)).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn leaving_the_queue_moves_later_jobs_up() {
+        let queue = AnalysisQueue::new(0, 10);
+        let first = queue.join().unwrap();
+        let mut second = queue.join().unwrap();
+        let mut third = queue.join().unwrap();
+
+        assert_eq!(third.next_status(None).await.err(), Some(3));
+        drop(first);
+        assert_eq!(third.next_status(Some(3)).await.err(), Some(2));
+        assert_eq!(second.next_status(None).await.err(), Some(1));
+    }
+}
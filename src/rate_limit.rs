@@ -0,0 +1,200 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimits {
+    pub window: Duration,
+    pub global: usize,
+    pub per_guild: usize,
+    pub per_user: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitScope {
+    Global,
+    Guild,
+    User,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimited {
+    pub scope: LimitScope,
+    pub retry_after: Duration,
+}
+
+#[derive(Debug)]
+struct Request {
+    id: u64,
+    time: Instant,
+    guild_id: u64,
+    user_id: u64,
+}
+
+/// Sliding window rate limiter, with separate limits for the whole bot, each guild and each user
+pub struct RateLimiter {
+    limits: RateLimits,
+    // All requests within the window, oldest first
+    requests: Mutex<VecDeque<Request>>,
+    next_id: AtomicU64,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        RateLimiter {
+            limits,
+            requests: Mutex::new(VecDeque::new()),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Reserve one request for the user, if it's within all the limits
+    /// The request is given back if the reservation is dropped without being committed
+    pub fn try_reserve(&self, guild_id: u64, user_id: u64) -> Result<Reservation<'_>, RateLimited> {
+        let now = Instant::now();
+        let mut requests = self.requests.lock().unwrap();
+        while requests
+            .front()
+            .is_some_and(|request| now.duration_since(request.time) >= self.limits.window)
+        {
+            requests.pop_front();
+        }
+
+        let checks = [
+            (
+                LimitScope::User,
+                self.retry_after(&requests, now, self.limits.per_user, |r| {
+                    r.user_id == user_id
+                }),
+            ),
+            (
+                LimitScope::Guild,
+                self.retry_after(&requests, now, self.limits.per_guild, |r| {
+                    r.guild_id == guild_id
+                }),
+            ),
+            (
+                LimitScope::Global,
+                self.retry_after(&requests, now, self.limits.global, |_| true),
+            ),
+        ];
+        for (scope, retry_after) in checks {
+            if let Some(retry_after) = retry_after {
+                return Err(RateLimited { scope, retry_after });
+            }
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        requests.push_back(Request {
+            id,
+            time: now,
+            guild_id,
+            user_id,
+        });
+        Ok(Reservation {
+            limiter: self,
+            id,
+            committed: false,
+        })
+    }
+
+    /// If the requests matching `is_in_scope` are at the limit, how long until there is room again
+    fn retry_after(
+        &self,
+        requests: &VecDeque<Request>,
+        now: Instant,
+        limit: usize,
+        is_in_scope: impl Fn(&Request) -> bool,
+    ) -> Option<Duration> {
+        // Once the oldest request that keeps us at the limit expires, there is room again
+        let blocking_request = requests
+            .iter()
+            .filter(|request| is_in_scope(request))
+            .rev()
+            .nth(limit.saturating_sub(1))?;
+        Some((blocking_request.time + self.limits.window).saturating_duration_since(now))
+    }
+}
+
+pub struct Reservation<'a> {
+    limiter: &'a RateLimiter,
+    id: u64,
+    committed: bool,
+}
+
+impl Reservation<'_> {
+    /// Count the request against the limits. Call this once the request has succeeded
+    pub fn commit(mut self) {
+        self.committed = true;
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        if !self.committed {
+            let mut requests = self.limiter.requests.lock().unwrap();
+            requests.retain(|request| request.id != self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(window: Duration) -> RateLimiter {
+        RateLimiter::new(RateLimits {
+            window,
+            global: 4,
+            per_guild: 3,
+            per_user: 2,
+        })
+    }
+
+    #[test]
+    fn user_limit() {
+        let limiter = limiter(Duration::from_secs(60));
+        limiter.try_reserve(1, 1).unwrap().commit();
+        limiter.try_reserve(1, 1).unwrap().commit();
+        let limited = limiter.try_reserve(1, 1).err().unwrap();
+        assert_eq!(limited.scope, LimitScope::User);
+        assert!(limited.retry_after <= Duration::from_secs(60));
+        assert!(limiter.try_reserve(1, 2).is_ok());
+    }
+
+    #[test]
+    fn guild_and_global_limits() {
+        let limiter = limiter(Duration::from_secs(60));
+        for user_id in 1..=3 {
+            limiter.try_reserve(1, user_id).unwrap().commit();
+        }
+        assert_eq!(
+            limiter.try_reserve(1, 4).err().unwrap().scope,
+            LimitScope::Guild
+        );
+        limiter.try_reserve(2, 4).unwrap().commit();
+        assert_eq!(
+            limiter.try_reserve(3, 5).err().unwrap().scope,
+            LimitScope::Global
+        );
+    }
+
+    #[test]
+    fn uncommitted_reservation_is_given_back() {
+        let limiter = limiter(Duration::from_secs(60));
+        let reservation = limiter.try_reserve(1, 1).unwrap();
+        limiter.try_reserve(1, 1).unwrap().commit();
+        assert!(limiter.try_reserve(1, 1).is_err());
+        drop(reservation);
+        assert!(limiter.try_reserve(1, 1).is_ok());
+    }
+
+    #[test]
+    fn requests_expire_after_window() {
+        let limiter = limiter(Duration::ZERO);
+        for _ in 0..10 {
+            limiter.try_reserve(1, 1).unwrap().commit();
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use serenity::builder::CreateEmbed;
+use serenity::client::Context;
+use serenity::http::Typing;
+use serenity::model::application::interaction::application_command::ApplicationCommandInteraction;
+use serenity::model::channel::Message;
+use serenity::model::id::{ChannelId, GuildId, RoleId};
+use serenity::model::mention::Mentionable;
+use serenity::model::prelude::AttachmentType;
+use serenity::model::user::User;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+// Discord only accepts responses to an interaction for 15 minutes. Leave a margin for slow requests
+const INTERACTION_RESPONSE_TIME: Duration = Duration::from_secs(14 * 60);
+
+/// Where an analysis request came from, either a `!` command or a slash command
+pub enum Source<'a> {
+    Message(&'a Message),
+    // The interaction must already have a deferred response
+    Interaction {
+        interaction: &'a ApplicationCommandInteraction,
+        // Whether the deferred response has been replaced with a real message yet
+        responded: AtomicBool,
+        received: Instant,
+    },
+}
+
+/// A user's analysis request, which the bot responds to with one or more replies
+pub struct Request<'a> {
+    pub ctx: &'a Context,
+    pub source: Source<'a>,
+}
+
+impl<'a> Request<'a> {
+    pub fn from_message(ctx: &'a Context, msg: &'a Message) -> Self {
+        Request {
+            ctx,
+            source: Source::Message(msg),
+        }
+    }
+
+    pub fn from_interaction(
+        ctx: &'a Context,
+        interaction: &'a ApplicationCommandInteraction,
+    ) -> Self {
+        Request {
+            ctx,
+            source: Source::Interaction {
+                interaction,
+                responded: AtomicBool::new(false),
+                received: Instant::now(),
+            },
+        }
+    }
+
+    pub fn author(&self) -> &User {
+        match &self.source {
+            Source::Message(msg) => &msg.author,
+            Source::Interaction { interaction, .. } => &interaction.user,
+        }
+    }
+
+    pub fn guild_id(&self) -> Option<GuildId> {
+        match &self.source {
+            Source::Message(msg) => msg.guild_id,
+            Source::Interaction { interaction, .. } => interaction.guild_id,
+        }
+    }
+
+    pub fn channel_id(&self) -> ChannelId {
+        match &self.source {
+            Source::Message(msg) => msg.channel_id,
+            Source::Interaction { interaction, .. } => interaction.channel_id,
+        }
+    }
+
+    /// The author's roles in the guild, or none outside of a guild
+    pub fn role_ids(&self) -> &[RoleId] {
+        match &self.source {
+            Source::Message(msg) => msg
+                .member
+                .as_ref()
+                .map_or(&[], |member| member.roles.as_slice()),
+            Source::Interaction { interaction, .. } => interaction
+                .member
+                .as_ref()
+                .map_or(&[], |member| member.roles.as_slice()),
+        }
+    }
+
+    pub async fn reply(&self, content: impl ToString) -> serenity::Result<Message> {
+        self.reply_with_files(content, vec![]).await
+    }
+
+    pub async fn reply_with_files(
+        &self,
+        content: impl ToString,
+        files: Vec<AttachmentType<'static>>,
+    ) -> serenity::Result<Message> {
+        self.reply_with_embeds(content, vec![], files).await
+    }
+
+    pub async fn reply_with_embeds(
+        &self,
+        content: impl ToString,
+        embeds: Vec<CreateEmbed>,
+        files: Vec<AttachmentType<'static>>,
+    ) -> serenity::Result<Message> {
+        let content = content.to_string();
+        match &self.source {
+            // Long analyses can outlive the interaction, so they are posted in the channel instead
+            Source::Interaction {
+                interaction,
+                received,
+                ..
+            } if received.elapsed() >= INTERACTION_RESPONSE_TIME => {
+                interaction
+                    .channel_id
+                    .send_message(&self.ctx.http, |m| {
+                        m.content(format!("{} {}", interaction.user.mention(), content));
+                        m.set_embeds(embeds);
+                        m.add_files(files);
+                        m
+                    })
+                    .await
+            }
+            Source::Message(msg) => {
+                msg.channel_id
+                    .send_message(&self.ctx.http, |m| {
+                        m.content(content);
+                        m.reference_message(*msg);
+                        m.set_embeds(embeds);
+                        m.add_files(files);
+                        m
+                    })
+                    .await
+            }
+            // The first reply replaces the "thinking" response, and later replies are follow-ups
+            Source::Interaction {
+                interaction,
+                responded,
+                ..
+            } => {
+                let first_reply = !responded.swap(true, Ordering::SeqCst);
+                if first_reply && files.is_empty() {
+                    return interaction
+                        .edit_original_interaction_response(&self.ctx.http, |r| {
+                            r.content(content).set_embeds(embeds)
+                        })
+                        .await;
+                }
+                // Attachments can only be sent as follow-ups, so the "thinking" response is removed instead
+                if first_reply {
+                    interaction
+                        .delete_original_interaction_response(&self.ctx.http)
+                        .await?;
+                }
+                interaction
+                    .create_followup_message(&self.ctx.http, |f| {
+                        f.content(content);
+                        f.add_embeds(embeds);
+                        f.add_files(files);
+                        f
+                    })
+                    .await
+            }
+        }
+    }
+
+    /// Edit a message previously sent with `reply`
+    pub async fn edit_reply(
+        &self,
+        message: &mut Message,
+        content: impl ToString,
+    ) -> serenity::Result<()> {
+        let content = content.to_string();
+        match &self.source {
+            Source::Interaction {
+                interaction,
+                received,
+                ..
+            } if received.elapsed() < INTERACTION_RESPONSE_TIME => {
+                *message = interaction
+                    .edit_followup_message(&self.ctx.http, message.id, |f| f.content(content))
+                    .await?;
+                Ok(())
+            }
+            _ => message.edit(self.ctx, |m| m.content(content)).await,
+        }
+    }
+
+    /// Show the typing indicator. Slash commands already show that the bot is thinking
+    pub fn start_typing(&self) -> serenity::Result<Option<Typing>> {
+        match &self.source {
+            Source::Message(msg) => Ok(Some(Typing::start(
+                self.ctx.http.clone(),
+                msg.channel_id.0,
+            )?)),
+            Source::Interaction { .. } => Ok(None),
+        }
+    }
+}
@@ -0,0 +1,250 @@
+use crate::aws::SearchSettings;
+use crate::cache;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+pub const MAX_ROLLOUT_DEPTH: u16 = 1000;
+pub const MAX_ROLLOUT_TEMPERATURE: f64 = 1.0;
+
+/// Search settings picked by a user or a guild, which replace the defaults
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct SearchOverrides {
+    pub nodes: Option<u64>,
+    pub rollout_depth: Option<u16>,
+    pub rollout_temperature: Option<f64>,
+}
+
+impl SearchOverrides {
+    /// Parse an option such as `nodes=300k`
+    /// Returns `None` if it isn't a search option, or an error message for the user if the value is invalid
+    pub fn parse_option(&mut self, key: &str, value: &str) -> Option<Result<(), String>> {
+        let result = match key {
+            "nodes" => match parse_node_count(value) {
+                Some(nodes) => {
+                    self.nodes = Some(nodes);
+                    Ok(())
+                }
+                None => Err(format!("Couldn't read node count \"{value}\"")),
+            },
+            "depth" => match value.parse::<u16>() {
+                Ok(depth) if depth <= MAX_ROLLOUT_DEPTH => {
+                    self.rollout_depth = Some(depth);
+                    Ok(())
+                }
+                _ => Err(format!(
+                    "Rollout depth must be between 0 and {MAX_ROLLOUT_DEPTH}"
+                )),
+            },
+            "temperature" => match value.parse::<f64>() {
+                Ok(temperature) if (0.0..=MAX_ROLLOUT_TEMPERATURE).contains(&temperature) => {
+                    self.rollout_temperature = Some(temperature);
+                    Ok(())
+                }
+                _ => Err(format!(
+                    "Rollout temperature must be between 0 and {MAX_ROLLOUT_TEMPERATURE}"
+                )),
+            },
+            _ => return None,
+        };
+        Some(result)
+    }
+
+    pub fn apply_to(&self, settings: SearchSettings) -> SearchSettings {
+        SearchSettings {
+            nodes: self.nodes.unwrap_or(settings.nodes),
+            rollout_depth: self.rollout_depth.unwrap_or(settings.rollout_depth),
+            rollout_temperature: self
+                .rollout_temperature
+                .unwrap_or(settings.rollout_temperature),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == SearchOverrides::default()
+    }
+}
+
+/// Parse a node count, allowing suffixes such as in `300k` or `1.5m`
+pub fn parse_node_count(value: &str) -> Option<u64> {
+    let lowercase = value.to_lowercase();
+    let (number, multiplier) = if let Some(number) = lowercase.strip_suffix('k') {
+        (number, 1_000.0)
+    } else if let Some(number) = lowercase.strip_suffix('m') {
+        (number, 1_000_000.0)
+    } else {
+        return lowercase.parse().ok();
+    };
+    let nodes = number.parse::<f64>().ok()? * multiplier;
+    if nodes.is_finite() && nodes >= 0.0 {
+        Some(nodes.round() as u64)
+    } else {
+        None
+    }
+}
+
+/// Parse a duration in seconds, allowing suffixes such as in `90s` or `2m`
+pub fn parse_duration(value: &str) -> Option<Duration> {
+    let lowercase = value.to_lowercase();
+    let (number, multiplier) = if let Some(number) = lowercase.strip_suffix('s') {
+        (number, 1.0)
+    } else if let Some(number) = lowercase.strip_suffix('m') {
+        (number, 60.0)
+    } else {
+        (lowercase.as_str(), 1.0)
+    };
+    let seconds = number.parse::<f64>().ok()? * multiplier;
+    Duration::try_from_secs_f64(seconds).ok()
+}
+
+/// Default search settings for each guild, set by its admins and saved to a file
+pub struct GuildDefaults {
+    path: PathBuf,
+    defaults: Mutex<HashMap<u64, SearchOverrides>>,
+    // Held while changing the defaults, so that writes happen in the same order as the changes
+    file_lock: tokio::sync::Mutex<()>,
+}
+
+impl GuildDefaults {
+    pub fn load(path: PathBuf) -> io::Result<Self> {
+        let defaults = match std::fs::read(&path) {
+            Ok(contents) => serde_json::from_slice(&contents)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => return Err(err),
+        };
+        Ok(GuildDefaults {
+            path,
+            defaults: Mutex::new(defaults),
+            file_lock: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    pub fn get(&self, guild_id: u64) -> SearchOverrides {
+        self.defaults
+            .lock()
+            .unwrap()
+            .get(&guild_id)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Set the guild's defaults, or remove them if they are empty
+    pub async fn set(&self, guild_id: u64, overrides: SearchOverrides) -> io::Result<()> {
+        let _file_guard = self.file_lock.lock().await;
+        let mut defaults = self.defaults.lock().unwrap().clone();
+        if overrides.is_empty() {
+            defaults.remove(&guild_id);
+        } else {
+            defaults.insert(guild_id, overrides);
+        }
+        let contents = serde_json::to_vec_pretty(&defaults)?;
+        cache::write_atomically(&self.path, &self.path.with_extension("tmp"), &contents).await?;
+        // Only use the new defaults once they are saved
+        *self.defaults.lock().unwrap() = defaults;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn node_counts() {
+        assert_eq!(parse_node_count("5000"), Some(5000));
+        assert_eq!(parse_node_count("300k"), Some(300_000));
+        assert_eq!(parse_node_count("1.5M"), Some(1_500_000));
+        assert_eq!(parse_node_count("-1k"), None);
+        assert_eq!(parse_node_count("k"), None);
+        assert_eq!(parse_node_count("many"), None);
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("90"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("90s"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("2m"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_duration("-5s"), None);
+        assert_eq!(parse_duration("1e300"), None);
+        assert_eq!(parse_duration("inf"), None);
+    }
+
+    #[test]
+    fn parse_options() {
+        let mut overrides = SearchOverrides::default();
+        assert_eq!(overrides.parse_option("nodes", "300k"), Some(Ok(())));
+        assert_eq!(overrides.parse_option("depth", "20"), Some(Ok(())));
+        assert_eq!(overrides.parse_option("temperature", "0.5"), Some(Ok(())));
+        assert_eq!(
+            overrides,
+            SearchOverrides {
+                nodes: Some(300_000),
+                rollout_depth: Some(20),
+                rollout_temperature: Some(0.5),
+            }
+        );
+
+        assert!(matches!(
+            overrides.parse_option("depth", "5000"),
+            Some(Err(_))
+        ));
+        assert!(matches!(
+            overrides.parse_option("temperature", "2"),
+            Some(Err(_))
+        ));
+        assert!(matches!(
+            overrides.parse_option("nodes", "lots"),
+            Some(Err(_))
+        ));
+        assert_eq!(overrides.parse_option("komi", "2"), None);
+        // Invalid values leave the previous setting alone
+        assert_eq!(overrides.rollout_depth, Some(20));
+    }
+
+    #[test]
+    fn overrides_replace_defaults() {
+        let defaults = SearchSettings {
+            nodes: 1000,
+            rollout_depth: 0,
+            rollout_temperature: 0.25,
+        };
+        let overrides = SearchOverrides {
+            nodes: Some(5000),
+            ..SearchOverrides::default()
+        };
+        assert_eq!(
+            overrides.apply_to(defaults),
+            SearchSettings {
+                nodes: 5000,
+                ..defaults
+            }
+        );
+        assert!(SearchOverrides::default().is_empty());
+        assert!(!overrides.is_empty());
+    }
+
+    #[tokio::test]
+    async fn guild_defaults_are_saved() {
+        let path =
+            std::env::temp_dir().join(format!("guild_defaults_test_{}.json", std::process::id()));
+        let overrides = SearchOverrides {
+            nodes: Some(5000),
+            ..SearchOverrides::default()
+        };
+        let guild_defaults = GuildDefaults::load(path.clone()).unwrap();
+        guild_defaults.set(1, overrides).await.unwrap();
+        guild_defaults
+            .set(2, SearchOverrides::default())
+            .await
+            .unwrap();
+
+        let reloaded = GuildDefaults::load(path.clone()).unwrap();
+        assert_eq!(reloaded.get(1), overrides);
+        assert!(reloaded.get(2).is_empty());
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
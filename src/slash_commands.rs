@@ -0,0 +1,312 @@
+use crate::request::Request;
+use crate::search_options::{SearchOverrides, MAX_ROLLOUT_DEPTH, MAX_ROLLOUT_TEMPERATURE};
+use crate::{
+    analyze_playtak_game, analyze_ptn_attachment, analyze_ptn_unsized, analyze_tps_unsized,
+    PtnOptions, TpsOptions, MAX_ANALYSIS_TIME, MIN_ANALYSIS_TIME,
+};
+use serenity::builder::CreateApplicationCommandOption;
+use serenity::client::Context;
+use serenity::framework::standard::CommandResult;
+use serenity::model::application::command::{Command, CommandOptionType};
+use serenity::model::application::interaction::application_command::{
+    ApplicationCommandInteraction, CommandDataOption,
+};
+use serenity::model::application::interaction::InteractionResponseType;
+use serenity::model::id::AttachmentId;
+use std::str::FromStr;
+use std::time::Duration;
+use tiltak::position::Komi;
+
+/// Register the slash commands with Discord, replacing any previously registered ones
+pub async fn register(ctx: &Context) -> serenity::Result<()> {
+    Command::set_global_application_commands(&ctx.http, |commands| {
+        commands
+            .create_application_command(|command| {
+                command
+                    .name("ping")
+                    .description("Check that the bot is running")
+            })
+            .create_application_command(|command| {
+                command
+                    .name("analyze")
+                    .description("Analyze a game or position with Tiltak")
+                    .create_option(|option| {
+                        option
+                            .name("ptn")
+                            .description("Analyze every move of a game")
+                            .kind(CommandOptionType::SubCommand)
+                            .create_sub_option(|option| {
+                                option
+                                    .name("ptn")
+                                    .description("The game's PTN")
+                                    .kind(CommandOptionType::String)
+                            })
+                            .create_sub_option(|option| {
+                                option
+                                    .name("file")
+                                    .description("A .ptn or .txt file with the game")
+                                    .kind(CommandOptionType::Attachment)
+                            })
+                            .create_sub_option(|option| {
+                                option
+                                    .name("game")
+                                    .description("Which game to analyze, if the PTN has several")
+                                    .kind(CommandOptionType::Integer)
+                                    .min_int_value(1)
+                            })
+                            .create_sub_option(nodes_option)
+                            .create_sub_option(depth_option)
+                            .create_sub_option(temperature_option)
+                            .create_sub_option(time_option)
+                            .create_sub_option(slatebot_option)
+                            .create_sub_option(komi_option)
+                            .create_sub_option(eval_komi_option)
+                    })
+                    .create_option(|option| {
+                        option
+                            .name("game")
+                            .description("Analyze every move of a game from Playtak")
+                            .kind(CommandOptionType::SubCommand)
+                            .create_sub_option(|option| {
+                                option
+                                    .name("id")
+                                    .description("The Playtak game id")
+                                    .kind(CommandOptionType::Integer)
+                                    .min_int_value(1)
+                                    .required(true)
+                            })
+                            .create_sub_option(nodes_option)
+                            .create_sub_option(depth_option)
+                            .create_sub_option(temperature_option)
+                            .create_sub_option(time_option)
+                            .create_sub_option(slatebot_option)
+                            .create_sub_option(komi_option)
+                            .create_sub_option(eval_komi_option)
+                    })
+                    .create_option(|option| {
+                        option
+                            .name("tps")
+                            .description("Analyze a single position")
+                            .kind(CommandOptionType::SubCommand)
+                            .create_sub_option(|option| {
+                                option
+                                    .name("tps")
+                                    .description("The position, as a TPS string")
+                                    .kind(CommandOptionType::String)
+                                    .required(true)
+                            })
+                            .create_sub_option(nodes_option)
+                            .create_sub_option(depth_option)
+                            .create_sub_option(temperature_option)
+                            .create_sub_option(komi_option)
+                            .create_sub_option(eval_komi_option)
+                    })
+            })
+    })
+    .await?;
+    Ok(())
+}
+
+fn nodes_option(
+    option: &mut CreateApplicationCommandOption,
+) -> &mut CreateApplicationCommandOption {
+    option
+        .name("nodes")
+        .description("Number of nodes to search per position")
+        .kind(CommandOptionType::Integer)
+        .min_int_value(1)
+}
+
+fn depth_option(
+    option: &mut CreateApplicationCommandOption,
+) -> &mut CreateApplicationCommandOption {
+    option
+        .name("depth")
+        .description("Rollout depth, where 0 uses only the evaluation function")
+        .kind(CommandOptionType::Integer)
+        .min_int_value(0)
+        .max_int_value(MAX_ROLLOUT_DEPTH)
+}
+
+fn temperature_option(
+    option: &mut CreateApplicationCommandOption,
+) -> &mut CreateApplicationCommandOption {
+    option
+        .name("temperature")
+        .description("Rollout temperature, where higher values play more random rollouts")
+        .kind(CommandOptionType::Number)
+        .min_number_value(0.0)
+        .max_number_value(MAX_ROLLOUT_TEMPERATURE)
+}
+
+fn time_option(option: &mut CreateApplicationCommandOption) -> &mut CreateApplicationCommandOption {
+    option
+        .name("time")
+        .description("Seconds to spend on the whole game, instead of a node count per position")
+        .kind(CommandOptionType::Integer)
+        .min_int_value(MIN_ANALYSIS_TIME.as_secs())
+        .max_int_value(MAX_ANALYSIS_TIME.as_secs())
+}
+
+fn slatebot_option(
+    option: &mut CreateApplicationCommandOption,
+) -> &mut CreateApplicationCommandOption {
+    option
+        .name("slatebot")
+        .description("Search with full rollouts, but far fewer nodes")
+        .kind(CommandOptionType::Boolean)
+}
+
+fn komi_option(option: &mut CreateApplicationCommandOption) -> &mut CreateApplicationCommandOption {
+    option
+        .name("komi")
+        .description("Komi to analyze with, such as 2 or 2.5")
+        .kind(CommandOptionType::String)
+}
+
+fn eval_komi_option(
+    option: &mut CreateApplicationCommandOption,
+) -> &mut CreateApplicationCommandOption {
+    option
+        .name("eval_komi")
+        .description("Komi for the evaluation, if the game's komi has no tuned evaluation")
+        .kind(CommandOptionType::String)
+}
+
+pub async fn handle(ctx: &Context, command: &ApplicationCommandInteraction) -> CommandResult {
+    println!("Received /{} from {}", command.data.name, command.user.name);
+    // Analysis takes much longer than Discord's 3 second limit for responding
+    command
+        .create_interaction_response(&ctx.http, |response| {
+            response.kind(InteractionResponseType::DeferredChannelMessageWithSource)
+        })
+        .await?;
+    let request = Request::from_interaction(ctx, command);
+
+    match command.data.name.as_str() {
+        "ping" => {
+            request.reply("Pong!").await?;
+            Ok(())
+        }
+        "analyze" => analyze(&request, command).await,
+        name => {
+            request.reply(format!("Unknown command /{name}")).await?;
+            Ok(())
+        }
+    }
+}
+
+async fn analyze(request: &Request<'_>, command: &ApplicationCommandInteraction) -> CommandResult {
+    if request.guild_id().is_none() {
+        request
+            .reply("Analysis is only available in specific channels.")
+            .await?;
+        return Ok(());
+    }
+    let Some(subcommand) = command.data.options.first() else {
+        request.reply("Missing subcommand.").await?;
+        return Ok(());
+    };
+    let options = &subcommand.options;
+
+    let komi = match string_option(options, "komi") {
+        Some(value) => match Komi::from_str(value) {
+            Ok(komi) => Some(komi),
+            Err(_) => {
+                request
+                    .reply(format!("Couldn't analyze with {value} komi"))
+                    .await?;
+                return Ok(());
+            }
+        },
+        None => None,
+    };
+    let eval_komi = match string_option(options, "eval_komi") {
+        Some(value) => match Komi::from_str(value) {
+            Ok(komi) => Some(komi),
+            Err(_) => {
+                request
+                    .reply(format!("Couldn't evaluate with {value} komi"))
+                    .await?;
+                return Ok(());
+            }
+        },
+        None => None,
+    };
+    // Discord checks the ranges too, but the options are validated the same way as for ! commands
+    let mut search = SearchOverrides::default();
+    for name in ["nodes", "depth", "temperature"] {
+        let Some(value) = option_value(options, name) else {
+            continue;
+        };
+        if let Some(Err(error)) = search.parse_option(name, &value.to_string()) {
+            request.reply(error).await?;
+            return Ok(());
+        }
+    }
+    let ptn_options = PtnOptions {
+        slatebot: bool_option(options, "slatebot").unwrap_or(false),
+        search,
+        komi,
+        eval_komi,
+        game: integer_option(options, "game").map(|game| game as usize),
+        time: integer_option(options, "time").map(|seconds| Duration::from_secs(seconds as u64)),
+    };
+
+    match subcommand.name.as_str() {
+        "ptn" => {
+            let attachment = string_option(options, "file")
+                .and_then(|id| id.parse().ok())
+                .and_then(|id| command.data.resolved.attachments.get(&AttachmentId(id)));
+            match (string_option(options, "ptn"), attachment) {
+                (Some(ptn), _) => analyze_ptn_unsized(request, ptn, ptn_options).await,
+                (None, Some(attachment)) => {
+                    analyze_ptn_attachment(request, attachment, ptn_options).await
+                }
+                (None, None) => {
+                    request.reply("No PTN provided.").await?;
+                    Ok(())
+                }
+            }
+        }
+        "game" => {
+            let game_id = integer_option(options, "id").unwrap_or_default() as u64;
+            analyze_playtak_game(request, game_id, ptn_options).await
+        }
+        "tps" => {
+            let tps = string_option(options, "tps").unwrap_or_default();
+            let mut tps_options = TpsOptions {
+                eval_komi,
+                search,
+                ..TpsOptions::default()
+            };
+            if let Some(komi) = komi {
+                tps_options.komi = komi;
+            }
+            analyze_tps_unsized(request, tps, tps_options).await
+        }
+        name => {
+            request.reply(format!("Unknown subcommand {name}")).await?;
+            Ok(())
+        }
+    }
+}
+
+fn option_value<'a>(options: &'a [CommandDataOption], name: &str) -> Option<&'a serde_json::Value> {
+    options
+        .iter()
+        .find(|option| option.name == name)
+        .and_then(|option| option.value.as_ref())
+}
+
+fn string_option<'a>(options: &'a [CommandDataOption], name: &str) -> Option<&'a str> {
+    option_value(options, name).and_then(|value| value.as_str())
+}
+
+fn integer_option(options: &[CommandDataOption], name: &str) -> Option<i64> {
+    option_value(options, name).and_then(|value| value.as_i64())
+}
+
+fn bool_option(options: &[CommandDataOption], name: &str) -> Option<bool> {
+    option_value(options, name).and_then(|value| value.as_bool())
+}
@@ -0,0 +1,324 @@
+use crate::aws::{Candidate, Event, Output, TimeControl};
+use crate::backend::AnalysisBackend;
+use log::{debug, warn};
+use serenity::async_trait;
+use std::io;
+use std::process::Stdio;
+use std::sync::Mutex;
+use std::time::Instant;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tokio::sync::Semaphore;
+
+/// Runs the searches on an external engine that speaks TEI (Tak Engine Interface) over stdin/stdout
+/// Engine processes are kept alive between searches, and reused for later plies
+pub struct TeiBackend {
+    engine_path: String,
+    engine_args: Vec<String>,
+    idle_engines: Mutex<Vec<TeiEngine>>,
+    engine_slots: Semaphore,
+    max_engines: usize,
+}
+
+impl TeiBackend {
+    pub fn new(engine_path: String, engine_args: Vec<String>, max_engines: usize) -> Self {
+        TeiBackend {
+            engine_path,
+            engine_args,
+            idle_engines: Mutex::new(Vec::with_capacity(max_engines)),
+            engine_slots: Semaphore::new(max_engines),
+            max_engines,
+        }
+    }
+}
+
+#[async_trait]
+impl AnalysisBackend for TeiBackend {
+    async fn search(&self, event: Event) -> io::Result<Output> {
+        let _permit = self
+            .engine_slots
+            .acquire()
+            .await
+            .map_err(io::Error::other)?;
+
+        let idle_engine = self.idle_engines.lock().unwrap().pop();
+        let mut engine = match idle_engine {
+            Some(engine) => engine,
+            None => TeiEngine::spawn(&self.engine_path, &self.engine_args).await?,
+        };
+
+        // If anything goes wrong, the engine process is dropped and killed,
+        // because we don't know what state it's in
+        let output = engine.search(&event).await?;
+        self.idle_engines.lock().unwrap().push(engine);
+        Ok(output)
+    }
+
+    fn parallel_searches(&self) -> Option<usize> {
+        Some(self.max_engines)
+    }
+}
+
+struct TeiEngine {
+    // Held so that the process is killed when the engine is dropped
+    _child: Child,
+    stdin: ChildStdin,
+    stdout: Lines<BufReader<ChildStdout>>,
+}
+
+impl TeiEngine {
+    async fn spawn(engine_path: &str, engine_args: &[String]) -> io::Result<Self> {
+        let mut child = Command::new(engine_path)
+            .args(engine_args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        let stdin = child
+            .stdin
+            .take()
+            .ok_or(io::Error::other("Failed to open engine stdin"))?;
+        let stdout = child
+            .stdout
+            .take()
+            .ok_or(io::Error::other("Failed to open engine stdout"))?;
+
+        let mut engine = TeiEngine {
+            _child: child,
+            stdin,
+            stdout: BufReader::new(stdout).lines(),
+        };
+
+        engine.send("tei").await?;
+        while engine.read_line().await? != "teiok" {}
+        debug!("Started TEI engine {}", engine_path);
+
+        Ok(engine)
+    }
+
+    async fn send(&mut self, line: &str) -> io::Result<()> {
+        debug!("> {}", line);
+        self.stdin.write_all(line.as_bytes()).await?;
+        self.stdin.write_all(b"\n").await?;
+        self.stdin.flush().await
+    }
+
+    async fn read_line(&mut self) -> io::Result<String> {
+        match self.stdout.next_line().await? {
+            Some(line) => {
+                debug!("< {}", line);
+                Ok(line)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "TEI engine closed its output",
+            )),
+        }
+    }
+
+    async fn search(&mut self, event: &Event) -> io::Result<Output> {
+        let start_time = Instant::now();
+
+        // TEI has no notion of a separate evaluation komi, so the engine always uses the real komi
+        self.send(&format!(
+            "setoption name HalfKomi value {}",
+            (event.komi * 2.0).round() as i64
+        ))
+        .await?;
+        // Engines that don't support MultiPV ignore it, and only report their best move
+        self.send(&format!(
+            "setoption name MultiPV value {}",
+            event.multi_pv.max(1)
+        ))
+        .await?;
+        self.send(&format!("teinewgame {}", event.size)).await?;
+
+        let mut position_command = match &event.tps {
+            Some(tps) => format!("position tps {}", tps),
+            None => "position startpos".to_string(),
+        };
+        if !event.moves.is_empty() {
+            position_command.push_str(" moves ");
+            position_command.push_str(&event.moves.join(" "));
+        }
+        self.send(&position_command).await?;
+
+        match event.time_control {
+            TimeControl::FixedNodes(nodes) => self.send(&format!("go nodes {}", nodes)).await?,
+            TimeControl::Time(time_left, increment) => {
+                self.send(&format!(
+                    "go wtime {0} btime {0} winc {1} binc {1}",
+                    time_left.as_millis(),
+                    increment.as_millis()
+                ))
+                .await?
+            }
+            TimeControl::MoveTime(move_time) => {
+                self.send(&format!("go movetime {}", move_time.as_millis()))
+                    .await?
+            }
+        }
+
+        // One entry for each of the engine's lines, best first
+        let mut infos = vec![SearchInfo::default()];
+        loop {
+            let line = self.read_line().await?;
+            let mut words = line.split_whitespace();
+            match words.next() {
+                Some("info") => {
+                    let multi_pv = line
+                        .split_whitespace()
+                        .skip_while(|word| *word != "multipv")
+                        .nth(1)
+                        .and_then(|word| word.parse::<usize>().ok())
+                        .unwrap_or(1);
+                    // Ignore lines that weren't asked for
+                    if (1..=event.multi_pv.max(1)).contains(&multi_pv) {
+                        if infos.len() < multi_pv {
+                            infos.resize_with(multi_pv, SearchInfo::default);
+                        }
+                        infos[multi_pv - 1].update(words);
+                    }
+                }
+                Some("bestmove") => {
+                    if infos[0].pv.is_empty() {
+                        if let Some(best_move) = words.next() {
+                            infos[0].pv.push(best_move.to_string());
+                        }
+                    }
+                    break;
+                }
+                _ => warn!("Unexpected line from TEI engine: {}", line),
+            }
+        }
+
+        // TEI scores are for the side to move, while `Output` has the last mover's
+        let score = infos[0].score.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "TEI engine did not report a score",
+            )
+        })?;
+        let nodes = infos
+            .iter()
+            .map(|info| info.nodes)
+            .max()
+            .unwrap_or_default();
+        let candidates = infos
+            .iter()
+            .take(event.multi_pv)
+            .filter_map(|info| {
+                Some(Candidate {
+                    mv: info.pv.first()?.clone(),
+                    score: 1.0 - info.score?,
+                    visits: 0,
+                    pv: info.pv.clone(),
+                })
+            })
+            .collect();
+
+        Ok(Output {
+            pv: infos.swap_remove(0).pv,
+            score: 1.0 - score,
+            nodes,
+            mem_usage: 0,
+            time_taken: start_time.elapsed(),
+            candidates,
+        })
+    }
+}
+
+/// The most recent search information reported by the engine
+#[derive(Debug, Default)]
+struct SearchInfo {
+    score: Option<f32>, // Winning probability for the side to move
+    nodes: u64,
+    pv: Vec<String>,
+}
+
+impl SearchInfo {
+    fn update<'a>(&mut self, mut words: impl Iterator<Item = &'a str>) {
+        while let Some(word) = words.next() {
+            match word {
+                "nodes" => {
+                    if let Some(nodes) = words.next().and_then(|w| w.parse().ok()) {
+                        self.nodes = nodes;
+                    }
+                }
+                "score" => match (words.next(), words.next().and_then(|w| w.parse().ok())) {
+                    (Some("cp"), Some(cp)) => self.score = Some(cp_to_winning_probability(cp)),
+                    (Some("mate"), Some(mate)) => {
+                        self.score = Some(if mate > 0 { 1.0 } else { 0.0 })
+                    }
+                    _ => (),
+                },
+                "pv" => {
+                    // The pv is always the last field
+                    self.pv = words.by_ref().map(str::to_string).collect();
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+/// Tiltak reports its winning probability `p` as the centipawn score `200 * p - 100`
+fn cp_to_winning_probability(cp: i64) -> f32 {
+    ((cp as f32 + 100.0) / 200.0).clamp(0.0, 1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cp_scores() {
+        assert_eq!(cp_to_winning_probability(-100), 0.0);
+        assert_eq!(cp_to_winning_probability(0), 0.5);
+        assert_eq!(cp_to_winning_probability(60), 0.8);
+        assert_eq!(cp_to_winning_probability(1000), 1.0);
+    }
+
+    #[test]
+    fn info_lines() {
+        let mut info = SearchInfo::default();
+        info.update("depth 3 nodes 1500 score cp 20 time 100 pv a1 f6 c3".split_whitespace());
+        assert_eq!(info.nodes, 1500);
+        assert_eq!(info.score, Some(0.6));
+        assert_eq!(info.pv, ["a1", "f6", "c3"]);
+
+        // Later lines only replace what they report
+        info.update("nodes 3000 score mate -2".split_whitespace());
+        assert_eq!(info.nodes, 3000);
+        assert_eq!(info.score, Some(0.0));
+        assert_eq!(info.pv, ["a1", "f6", "c3"]);
+    }
+
+    // An engine that always reports the same search, whatever the position
+    fn scripted_engine(score_cp: i64) -> TeiBackend {
+        let script = format!(
+            "while read line; do case \"$line\" in \
+             tei) echo teiok ;; \
+             go*) echo \"info nodes 100 score cp {score_cp} pv d4\"; echo \"bestmove d4\" ;; \
+             esac; done"
+        );
+        TeiBackend::new("sh".to_string(), vec!["-c".to_string(), script], 1)
+    }
+
+    fn event(tps: &str) -> Event {
+        Event::for_test(4, Some(tps), &[])
+    }
+
+    #[tokio::test]
+    async fn white_win_is_above_half() {
+        // White, the side to move, can make a road in one move, so the engine's score is high
+        let output = scripted_engine(80)
+            .analyze(event("1,1,1,x/2,2,2,x/x4/x4 1 4"))
+            .await
+            .unwrap();
+        assert!(output.score > 0.5);
+        assert_eq!(output.pv, ["d4"]);
+        assert_eq!(output.candidates[0].score, output.score);
+    }
+}
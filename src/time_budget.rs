@@ -0,0 +1,98 @@
+// Splitting a total time budget between the positions of one or more games
+
+use board_game_traits::Position as PositionTrait;
+use std::time::Duration;
+use tiltak::position::Position;
+use tiltak::ptn::Game;
+
+// Every position gets a short search, even if it looks trivial
+const MIN_WEIGHT: f64 = 0.05;
+// The last plies before the game ended are usually forced
+const FORCED_ENDGAME_PLIES: usize = 2;
+
+/// How complex each position of the game is, relative to the others
+/// Positions with many legal moves get more time, while the opening and the last plies of a finished game get less
+pub fn position_weights<const S: usize>(game: &Game<Position<S>>) -> Vec<f64> {
+    let mut position = game.start_position.clone();
+    let mut weights = Vec::with_capacity(game.moves.len() + 1);
+    let mut legal_moves = vec![];
+    for ply in 0..=game.moves.len() {
+        let weight = if position.game_result().is_some() {
+            MIN_WEIGHT
+        } else {
+            legal_moves.clear();
+            position.generate_moves(&mut legal_moves);
+            // The first plies only place flats, so there is little to think about
+            let opening_factor = ((ply + 1) as f64 / (2 * S) as f64).min(1.0);
+            (legal_moves.len() as f64).sqrt() * opening_factor
+        };
+        weights.push(weight.max(MIN_WEIGHT));
+        if let Some(ptn_move) = game.moves.get(ply) {
+            position.do_move(ptn_move.mv);
+        }
+    }
+
+    if position.game_result().is_some() {
+        let forced_plies = weights.len().min(FORCED_ENDGAME_PLIES + 1);
+        let first_forced = weights.len() - forced_plies;
+        for weight in weights[first_forced..].iter_mut() {
+            *weight = (*weight / 2.0).max(MIN_WEIGHT);
+        }
+    }
+    weights
+}
+
+/// Split the budget between positions in proportion to their weights
+/// Up to `parallel_searches` positions are searched at once, so that many times the budget is available in total
+/// No position gets more than the whole budget, so the analysis finishes in roughly that time
+pub fn move_times(
+    budget: Duration,
+    weights: &[f64],
+    parallel_searches: Option<usize>,
+) -> Vec<Duration> {
+    let parallel_searches = parallel_searches
+        .unwrap_or(weights.len())
+        .clamp(1, weights.len().max(1));
+    let total_weight: f64 = weights.iter().sum();
+    let total_time = budget.as_secs_f64() * parallel_searches as f64;
+    weights
+        .iter()
+        .map(|weight| {
+            let seconds = total_time * weight / total_weight;
+            Duration::from_secs_f64(seconds.min(budget.as_secs_f64()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tiltak::ptn::ptn_parser;
+
+    #[test]
+    fn opening_positions_get_less_weight() {
+        let ptn = "[Size \"6\"]\n\n1. a1 f6 2. c3 d4 3. c4 d3";
+        let game = ptn_parser::parse_ptn::<Position<6>>(ptn).unwrap().remove(0);
+        let weights = position_weights(&game);
+        assert_eq!(weights.len(), game.moves.len() + 1);
+        assert!(weights.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn budget_is_split_by_weight() {
+        let budget = Duration::from_secs(10);
+        let times = move_times(budget, &[1.0, 1.0, 2.0], Some(1));
+        assert_eq!(times, [2.5, 2.5, 5.0].map(Duration::from_secs_f64).to_vec());
+    }
+
+    #[test]
+    fn no_position_gets_more_than_the_budget() {
+        let budget = Duration::from_secs(10);
+        let times = move_times(budget, &[1.0, 1.0, 2.0], None);
+        assert_eq!(
+            times,
+            [7.5, 7.5, 10.0].map(Duration::from_secs_f64).to_vec()
+        );
+        assert!(move_times(budget, &[], None).is_empty());
+    }
+}